@@ -0,0 +1,67 @@
+/* descriptor distance benches: each hamming kernel on one query versus many targets,
+   and matching real descriptors with the hamming kernels versus the squared euclidean kd-tree */
+
+use local_reverse_image_search::config::ExtractParams;
+use local_reverse_image_search::feature_matching::{bitarray_to_block, extract_from_image, get_matches, get_matches_kdtree};
+use local_reverse_image_search::hamming::{distances_with, Kernel};
+use local_reverse_image_search::store::Block;
+use local_reverse_image_search::utils::XorShift;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::GenericImageView;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+const NUM_TARGETS: usize = 1024;
+
+fn random_blocks(rng: &mut XorShift, n: usize) -> Vec<Block> {
+    (0..n).map(|_| {
+        let mut block: Block = [0; 64];
+        for byte in block.iter_mut() {
+            *byte = rng.below(256) as u8;
+        }
+        block
+    }).collect()
+}
+
+fn kernels(c: &mut Criterion) {
+
+    let mut rng = XorShift::new(7);
+    let query = random_blocks(&mut rng, 1)[0];
+    let targets = random_blocks(&mut rng, NUM_TARGETS);
+    let mut out = vec![0; NUM_TARGETS];
+
+    let mut group = c.benchmark_group("hamming_kernels");
+    group.throughput(Throughput::Elements(NUM_TARGETS as u64));
+
+    for kernel in [Kernel::Avx512, Kernel::Avx2, Kernel::Scalar].into_iter().filter(|k| k.is_supported()) {
+        group.bench_function(BenchmarkId::from_parameter(format!("{:?}", kernel)), |b| {
+            b.iter(|| distances_with(kernel, &query, &targets, &mut out))
+        });
+    }
+
+    group.finish();
+}
+
+fn matching(c: &mut Criterion) {
+
+    /* real akaze descriptors: the query image against a crop of itself */
+    let img = image::open(QUERY_IMG_PATH).unwrap();
+    let (w, h) = img.dimensions();
+    let pack = |img: &image::DynamicImage| -> Vec<Block> {
+        extract_from_image(img, &ExtractParams::default(), &Vec::new()).descriptors.iter().map(bitarray_to_block).collect()
+    };
+    let query = pack(&img);
+    let search = pack(&img.crop_imm(w / 4, h / 4, w / 2, h / 2));
+
+    let mut group = c.benchmark_group("matching");
+    group.throughput(Throughput::Elements((query.len() * search.len()) as u64));
+
+    group.bench_function("hamming", |b| b.iter(|| get_matches(0.5, &query, &search, &[])));
+    group.bench_function("kdtree_squared_euclidean", |b| b.iter(|| get_matches_kdtree(0.5, &query, &search)));
+
+    group.finish();
+}
+
+criterion_group!(benches, kernels, matching);
+criterion_main!(benches);
@@ -0,0 +1,74 @@
+/* warm cache throughput: every search image is already cached, so this measures
+   cache reads, deserialisation and matching across different numbers of workers,
+   with cache reads serialized (as behind the old mutex) and concurrent */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::Config;
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_query};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+use common::test_config;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::GenericImageView;
+use std::collections::BTreeSet;
+use std::path::Path;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+const NUM_IMAGES: u32 = 64;
+
+/// num_io_workers = 1 lets one worker at a time read the cache, like the mutex around it
+/// before workers read it concurrently, the baseline the lock-free reads are compared to
+fn bench_config(dir: &Path, num_workers: u32, num_io_workers: u32) -> Config {
+    Config { num_workers, num_io_workers, ..test_config(dir) }
+}
+
+/// writes NUM_IMAGES different crops of the query image, returns their paths
+fn write_corpus(dir: &Path) -> Vec<String> {
+
+    let img = image::open(QUERY_IMG_PATH).unwrap();
+    let (w, h) = img.dimensions();
+    let search = dir.join("search");
+    std::fs::create_dir_all(&search).unwrap();
+
+    (0..NUM_IMAGES).map(|i| {
+        let (x, y) = ((i * 7) % (w / 2), (i * 11) % (h / 2));
+        let path = search.join(format!("{:03}.png", i));
+        img.crop_imm(x, y, w / 2, h / 2).save(&path).unwrap();
+        path.to_string_lossy().to_string()
+    }).collect()
+}
+
+fn warm_cache(c: &mut Criterion) {
+
+    let dir = TempDir::new().unwrap();
+    let paths = write_corpus(dir.path());
+    let config = bench_config(dir.path(), 0, 0);
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    let query = extract_query(&cache, &config, &QUERY_IMG_PATH.to_string()).unwrap();
+    calculate_similarities(&cache, &config, &query, paths.clone()).unwrap();
+
+    let mut group = c.benchmark_group("warm_cache");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(NUM_IMAGES as u64));
+
+    /* the cpu count is often one of the others, and criterion doesn't allow the same id twice */
+    let worker_counts: BTreeSet<u32> = [1, 2, 4, 8, num_cpus::get() as u32].into_iter().collect();
+    for num_workers in worker_counts {
+        for (name, num_io_workers) in [("serialized", 1), ("concurrent", 0)] {
+            let config = bench_config(dir.path(), num_workers, num_io_workers);
+            group.bench_with_input(BenchmarkId::new(name, num_workers), &config, |b, config| {
+                b.iter(|| calculate_similarities(&cache, config, &query, paths.clone()).unwrap())
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, warm_cache);
+criterion_main!(benches);
@@ -0,0 +1,220 @@
+use crate::config::SearchRoot;
+use crate::error::{Error, Result};
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// what to do with each matched file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Copy,
+    Hardlink,
+    Symlink,
+
+    /// moves the file out of the search directory, falls back to copy and delete across filesystems
+    Move
+}
+
+impl Action {
+
+    /// whether the matched files themselves are changed (only moving does)
+    pub fn is_destructive(&self) -> bool {
+        *self == Action::Move
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Copy => write!(f, "copy"),
+            Action::Hardlink => write!(f, "hardlink"),
+            Action::Symlink => write!(f, "symlink"),
+            Action::Move => write!(f, "move")
+        }
+    }
+}
+
+/// "copy", "hardlink", "symlink" or "move", as given to --action
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Action, String> {
+        match s {
+            "copy" => Ok(Action::Copy),
+            "hardlink" => Ok(Action::Hardlink),
+            "symlink" => Ok(Action::Symlink),
+            "move" => Ok(Action::Move),
+            _ => Err(format!("expected copy, hardlink, symlink or move, got {}", s))
+        }
+    }
+}
+
+/// what to do when the target path is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Collision {
+    /// leave the existing file alone and don't act on the match
+    #[default]
+    Skip,
+
+    /// replace the existing file
+    Overwrite,
+
+    /// add a number to the file name, name_1.png, name_2.png, ...
+    Rename
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Collision::Skip => write!(f, "skip"),
+            Collision::Overwrite => write!(f, "overwrite"),
+            Collision::Rename => write!(f, "rename")
+        }
+    }
+}
+
+/// "skip", "overwrite" or "rename", as given to --on-collision
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Collision, String> {
+        match s {
+            "skip" => Ok(Collision::Skip),
+            "overwrite" => Ok(Collision::Overwrite),
+            "rename" => Ok(Collision::Rename),
+            _ => Err(format!("expected skip, overwrite or rename, got {}", s))
+        }
+    }
+}
+
+/// what happened to one matched file
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionStatus {
+    /// done (or would be done, in a dry run) to the target path
+    Done { target: PathBuf },
+
+    /// target path was taken and collisions are skipped
+    Skipped { target: PathBuf },
+
+    Failed { message: String }
+}
+
+/// a matched file and what happened to it
+#[derive(Debug, Clone)]
+pub struct ActionOutcome {
+    pub source: String,
+    pub status: ActionStatus
+}
+
+/// search roots (canonical) that matched file paths are made relative to, most specific first
+fn canonical_roots(roots: &[SearchRoot]) -> Vec<PathBuf> {
+    let mut canonical: Vec<PathBuf> = roots.iter().filter_map(|root| fs::canonicalize(root.path()).ok()).collect();
+    canonical.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+    canonical
+}
+
+/// where a matched file goes in target_dir: its path below the search root it was found in,
+/// just the file name if it isn't below any of them
+fn target_path(roots: &[PathBuf], target_dir: &Path, source: &str) -> PathBuf {
+    let source = Path::new(source);
+    let relative = roots.iter()
+                        .find_map(|root| source.strip_prefix(root).ok())
+                        .filter(|relative| relative.file_name().is_some())
+                        .unwrap_or_else(|| Path::new(source.file_name().unwrap_or(source.as_os_str())));
+    target_dir.join(relative)
+}
+
+/// name_1.png, name_2.png, ... for the first number that isn't taken
+fn renamed(target: &Path, taken: &dyn Fn(&Path) -> bool) -> PathBuf {
+    let stem = target.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = target.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..).map(|i| target.with_file_name(format!("{}_{}{}", stem, i, ext)))
+         .find(|candidate| !taken(candidate))
+         .unwrap()
+}
+
+/// copies, links or moves source to target, which is free (or is to be overwritten)
+fn apply(action: Action, source: &Path, target: &Path, overwrite: bool) -> io::Result<()> {
+
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    /* links and renames don't replace existing files everywhere, make room first */
+    if overwrite && fs::symlink_metadata(target).is_ok() {
+        fs::remove_file(target)?;
+    }
+
+    match action {
+        Action::Copy => fs::copy(source, target).map(|_| ()),
+        Action::Hardlink => fs::hard_link(source, target),
+        Action::Symlink => symlink(source, target),
+        Action::Move => fs::rename(source, target).or_else(|_| {
+            /* most likely another filesystem */
+            fs::copy(source, target)?;
+            fs::remove_file(source)
+        })
+    }
+}
+
+#[cfg(unix)]
+fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(source, target)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, target: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(source, target)
+}
+
+/// applies action to every matched file (canonical paths, as reported), putting them in target_dir
+/// with their paths below the search root they were found in, in a dry run nothing is changed
+/// but the outcomes are what would have happened, one file failing doesn't stop the others
+pub fn act_on_matches(action: Action, collision: Collision, dry_run: bool, roots: &[SearchRoot], target_dir: &Path, sources: &[&str]) -> Vec<ActionOutcome> {
+
+    let roots = canonical_roots(roots);
+
+    /* targets claimed by earlier matches, a dry run doesn't create them */
+    let mut claimed: HashSet<PathBuf> = HashSet::new();
+
+    sources.iter().map(|source| {
+
+        let target = target_path(&roots, target_dir, source);
+        let taken = |path: &Path| claimed.contains(path) || fs::symlink_metadata(path).is_ok();
+
+        let (target, overwrite) = match taken(&target) {
+            false => (target, false),
+            true => match collision {
+                Collision::Skip => return ActionOutcome { source: source.to_string(), status: ActionStatus::Skipped { target } },
+                Collision::Overwrite => (target, true),
+                Collision::Rename => (renamed(&target, &taken), false)
+            }
+        };
+
+        if Path::new(source) == target {
+            let message = "target is the matched file itself".to_string();
+            return ActionOutcome { source: source.to_string(), status: ActionStatus::Failed { message } }
+        }
+
+        let status = match dry_run {
+            true => ActionStatus::Done { target: target.clone() },
+            false => match apply(action, Path::new(source), &target, overwrite) {
+                Ok(_) => ActionStatus::Done { target: target.clone() },
+                Err(err) => ActionStatus::Failed { message: format!("unable to {} to {}: {}", action, target.display(), err) }
+            }
+        };
+        claimed.insert(target);
+
+        ActionOutcome { source: source.to_string(), status }
+    }).collect()
+}
+
+/// writes the matched files' paths to a list file, one per line
+pub fn write_list_file(filepath: &str, sources: &[&str]) -> Result<()> {
+    let list: String = sources.iter().map(|source| format!("{}\n", source)).collect();
+    fs::write(filepath, list).map_err(|source| Error::Output { path: filepath.to_string(), source })
+}
@@ -1,12 +1,224 @@
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use local_reverse_image_search::actions::{Action, Collision};
+use local_reverse_image_search::config::{Config, SortBy};
+use local_reverse_image_search::error::Error;
+use local_reverse_image_search::region::Region;
 
 #[derive(Debug, Parser)]
 pub struct ReverseImageSearchArgs {
-    /// path to query img file
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// path to query img file, - reads it from stdin, several (views of the same object) are searched for together
+    #[arg(short, long, num_args=1..)]
+    pub query_img_path: Vec<String>,
+
+    /// never open a file dialog, without a query image path this is an error
+    #[arg(long)]
+    pub no_gui: bool,
+
+    /// only search for this part of the query image (a single one), x,y,w,h in query image pixels
+    #[arg(long)]
+    pub roi: Option<Region>,
+
+    /// only search for the parts of the query image inside the polygons (or rects) of this mask file (toml)
+    #[arg(long)]
+    pub roi_mask: Option<String>,
+
+    /// only search these search directories (as written in search_dirs_paths), all of them if not given
+    #[arg(long)]
+    pub root: Vec<String>,
+
+    /// search these collections instead of search_dirs_paths, each on its own with results grouped by collection
+    #[arg(long, conflicts_with="root")]
+    pub collection: Vec<String>,
+
+    /// report at most this many matches, overrides results.top_k
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// leave out matches with fewer keypoint matches, overrides results.min_matches
+    #[arg(long)]
+    pub min_matches: Option<u32>,
+
+    /// minimum z-score of a match, overrides outlier_zscore_thresh
+    #[arg(long)]
+    pub min_score: Option<f32>,
+
+    /// only report matches whose path matches one of these gitignore style globs, overrides results.paths
+    #[arg(long)]
+    pub path_glob: Vec<String>,
+
+    /// order of the reported matches: score, path, mtime or size, overrides results.sort_by
+    #[arg(long)]
+    pub sort: Option<SortBy>,
+
+    /// copy, hardlink, symlink or move the matches into --target-dir, keeping their paths below the search directory
+    #[arg(long, requires="target_dir")]
+    pub action: Option<Action>,
+
+    /// directory --action puts the matches in
+    #[arg(long, requires="action")]
+    pub target_dir: Option<String>,
+
+    /// when a match's path in --target-dir is taken: skip, overwrite or rename (name_1.png)
+    #[arg(long, default_value_t=Collision::Skip)]
+    pub on_collision: Collision,
+
+    /// only print what --action would do
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// don't ask before moving or overwriting files
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// write the paths of the matches to this file, one per line
+    #[arg(long)]
+    pub list_file: Option<String>,
+
+    /// write images that couldn't be processed (and unreadable directory entries) to this file, as toml
+    #[arg(long)]
+    pub failure_log: Option<String>,
+
+    /// path to the project config file (config.toml if there is one), merged over the user config file
+    #[arg(short, long, global=true)]
+    pub config_file_path: Option<String>,
+
+    /// apply this [profile.<name>] from the config files, overrides LRIS_PROFILE
+    #[arg(short, long, global=true)]
+    pub profile: Option<String>,
+
+    /// override a config value, e.g. --set scan.max_depth=2 (values are toml, anything else is a string)
+    #[arg(long, global=true)]
+    pub set: Vec<String>
+}
+
+impl ReverseImageSearchArgs {
+
+    /// overrides config with the search and result options given on the command line
+    pub fn apply_to(&self, config: &mut Config) -> Result<(), Error> {
+
+        if !self.root.is_empty() {
+            if let Some(root) = self.root.iter().find(|root| !config.search_dirs_paths.iter().any(|r| r.path() == *root)) {
+                return Err(Error::Usage { message: format!("--root {} isn't one of the search_dirs_paths", root) })
+            }
+            config.search_dirs_paths.retain(|r| self.root.contains(r.path()));
+        }
+
+        if let Some(top_k) = self.top_k {
+            config.results.top_k = Some(top_k);
+        }
+        if let Some(min_matches) = self.min_matches {
+            config.results.min_matches = Some(min_matches);
+        }
+        if let Some(min_score) = self.min_score {
+            config.outlier_zscore_thresh = min_score;
+        }
+        if !self.path_glob.is_empty() {
+            config.results.paths = self.path_glob.clone();
+        }
+        if let Some(sort_by) = self.sort {
+            config.results.sort_by = sort_by;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// measure search quality (precision and recall@k, recall, MRR, mAP) and timing
+    Eval(EvalArgs),
+
+    /// inspect the configuration
+    Config(ConfigArgs),
+
+    /// manage collections: named sets of search directories with their own settings and cache
+    Collection(CollectionArgs),
+
+    /// maintain the cache
+    Cache(CacheArgs)
+}
+
+#[derive(Debug, Args)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    pub command: CacheCommand
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand {
+    /// rewrite the packed descriptor files (of the cache and every collection) without the
+    /// blocks of images that were extracted again since
+    Compact
+}
+
+#[derive(Debug, Args)]
+pub struct CollectionArgs {
+    #[command(subcommand)]
+    pub command: CollectionCommand
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CollectionCommand {
+    /// add a collection of search directories, searched with --collection <name>
+    Create {
+        name: String,
+
+        #[arg(required=true)]
+        dirs: Vec<String>,
+
+        /// a setting of the collection, e.g. -o resize_dimensions=[512,512] (valid_file_extensions,
+        /// resize_dimensions, ratio_test_ratio and extractor.*, values are toml)
+        #[arg(short, long)]
+        option: Vec<String>
+    },
+
+    /// print every collection with its search directories, settings and number of cached images
+    List,
+
+    /// remove a collection and everything cached for it (its images are left alone)
+    Drop {
+        name: String
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// print the effective config (defaults, config files, profile, LRIS_* environment variables
+    /// and --set overrides merged) with where each value came from
+    Show,
+
+    /// check the effective config for unknown keys, values out of range and missing search directories
+    Check
+}
+
+#[derive(Debug, Args)]
+pub struct EvalArgs {
+    /// path to ground truth file, built from synthetic augmentations of the search images if not given
     #[arg(short, long)]
-    pub query_img_path: Option<String>,
+    pub ground_truth_path: Option<String>,
+
+    /// number of search images to sample as synthetic queries
+    #[arg(short, long, default_value_t=20)]
+    pub num_queries: usize,
+
+    /// directory synthetic queries and their ground truth file are written to
+    #[arg(short, long, default_value_t=String::from(".eval"))]
+    pub eval_dir: String,
+
+    /// number of top ranked results used for precision@k and recall@k
+    #[arg(short='k', long, default_value_t=5)]
+    pub top_k: usize,
 
-    /// path to config file
-    #[arg(short, long, default_value_t=String::from("config.toml"))]
-    pub config_file_path: String
-}
\ No newline at end of file
+    /// seed for sampling queries and augmentations
+    #[arg(short, long, default_value_t=0)]
+    pub seed: u64
+}
@@ -0,0 +1,117 @@
+use crate::utils::XorShift;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgb, RgbImage};
+use std::fmt;
+
+/// synthetic transforms used to generate query images with known matches
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Augmentation {
+    Rotate90,
+    Rotate15,
+    ScaleDown,
+    ScaleUp,
+    Crop,
+    Recompress,
+    Brighten,
+    Darken,
+    Noise
+}
+
+impl Augmentation {
+
+    pub const ALL: [Augmentation; 9] = [
+        Augmentation::Rotate90,
+        Augmentation::Rotate15,
+        Augmentation::ScaleDown,
+        Augmentation::ScaleUp,
+        Augmentation::Crop,
+        Augmentation::Recompress,
+        Augmentation::Brighten,
+        Augmentation::Darken,
+        Augmentation::Noise
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Augmentation::Rotate90 => "rotate_90",
+            Augmentation::Rotate15 => "rotate_15",
+            Augmentation::ScaleDown => "scale_down",
+            Augmentation::ScaleUp => "scale_up",
+            Augmentation::Crop => "crop",
+            Augmentation::Recompress => "recompress",
+            Augmentation::Brighten => "brighten",
+            Augmentation::Darken => "darken",
+            Augmentation::Noise => "noise"
+        }
+    }
+
+    /// applies the transform, rng is only used by randomized transforms (noise)
+    pub fn apply(&self, img: &DynamicImage, rng: &mut XorShift) -> DynamicImage {
+
+        let (w, h) = img.dimensions();
+
+        match self {
+            Augmentation::Rotate90 => img.rotate90(),
+            Augmentation::Rotate15 => rotate(img, 15.0),
+            Augmentation::ScaleDown => img.resize((w / 2).max(1), (h / 2).max(1), FilterType::Triangle),
+            Augmentation::ScaleUp => img.resize(w * 2, h * 2, FilterType::Triangle),
+            Augmentation::Crop => img.crop_imm(w / 8, h / 8, (w * 3 / 4).max(1), (h * 3 / 4).max(1)),
+            Augmentation::Recompress => recompress(img, 20),
+            Augmentation::Brighten => img.brighten(40),
+            Augmentation::Darken => img.brighten(-40),
+            Augmentation::Noise => add_noise(img, 12, rng)
+        }
+    }
+}
+
+impl fmt::Display for Augmentation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// rotates about the image center by an arbitrary angle (nearest neighbour, black fill)
+pub fn rotate(img: &DynamicImage, degrees: f32) -> DynamicImage {
+
+    let src = img.to_rgb8();
+    let (w, h) = src.dimensions();
+    let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+
+    let out = RgbImage::from_fn(w, h, |x, y| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let sx = cos * dx + sin * dy + cx;
+        let sy = -sin * dx + cos * dy + cy;
+        match sx >= 0.0 && sy >= 0.0 && (sx as u32) < w && (sy as u32) < h {
+            true => *src.get_pixel(sx as u32, sy as u32),
+            false => Rgb([0, 0, 0])
+        }
+    });
+
+    DynamicImage::ImageRgb8(out)
+}
+
+/// adds uniform noise of +-amplitude to every channel
+pub fn add_noise(img: &DynamicImage, amplitude: i32, rng: &mut XorShift) -> DynamicImage {
+
+    let mut out = img.to_rgb8();
+    for px in out.pixels_mut() {
+        for c in px.0.iter_mut() {
+            let n = rng.below(2 * amplitude as usize + 1) as i32 - amplitude;
+            *c = (*c as i32 + n).clamp(0, 255) as u8;
+        }
+    }
+
+    DynamicImage::ImageRgb8(out)
+}
+
+/// writes img as a jpeg of the given quality and reads it back
+pub fn recompress(img: &DynamicImage, quality: u8) -> DynamicImage {
+
+    let mut buf: Vec<u8> = Vec::new();
+    match img.write_to(&mut buf, ImageOutputFormat::Jpeg(quality)) {
+        Ok(_) => image::load_from_memory(&buf).unwrap_or_else(|_| img.clone()),
+        Err(_) => img.clone()
+    }
+}
@@ -1,4 +1,13 @@
+use crate::config::ExtractParams;
+use crate::error::{self, Error, Failure};
+use crate::store::{DescriptorStore, PackedFeatures};
+
+use sled::{Batch, Db, Tree};
 use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use serde::{Serialize};
 use serde::{Serializer};
@@ -6,10 +15,232 @@ use akaze::KeyPoint;
 use serde::ser::SerializeStruct;
 use serde::de::{self, Deserialize, Deserializer, Visitor, MapAccess, SeqAccess};
 
+/// sled tree recording images that couldn't be processed, keyed by path
+pub const FAILURES_TREE: &str = "failures";
+
+/// sled tree holding a collection's entries (see Cache::collection)
+pub const ENTRIES_TREE: &str = "entries";
+
+/// on-disk store of extracted features (and of images that failed), cheap to clone
+/// and safe to share between threads without locking since sled synchronises itself,
+/// descriptors are also kept packed in a memory mapped store for warm searches
+#[derive(Clone)]
+pub struct Cache {
+    db: Db,
+    path: String,
+    entries: Tree,
+    failures: Tree,
+    store: Arc<DescriptorStore>
+}
+
+impl Cache {
+
+    pub fn open(path: &str) -> error::Result<Cache> {
+        let db = sled::open(path)?;
+        let entries: Tree = (*db).clone();
+        let failures = db.open_tree(FAILURES_TREE)?;
+        let store = Arc::new(DescriptorStore::open(&db, Path::new(path), "")?);
+        Ok(Cache { db, path: path.to_string(), entries, failures, store })
+    }
+
+    /// a separate cache in the same db, with its own entries, failures and packed
+    /// descriptors in trees (and a block file) whose names start with prefix
+    pub fn with_prefix(&self, prefix: &str) -> error::Result<Cache> {
+        let entries = self.db.open_tree(format!("{}{}", prefix, ENTRIES_TREE))?;
+        let failures = self.db.open_tree(format!("{}{}", prefix, FAILURES_TREE))?;
+        let store = Arc::new(DescriptorStore::open(&self.db, Path::new(&self.path), prefix)?);
+        Ok(Cache { db: self.db.clone(), path: self.path.clone(), entries, failures, store })
+    }
+
+    /// the db the cache is kept in
+    pub(crate) fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// directory the db (and block files) are in
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// number of cached entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// packed descriptors of previously cached images
+    pub fn store(&self) -> &DescriptorStore {
+        &self.store
+    }
+
+    /// the entry cached for path, CacheCorrupt if it can't be read back
+    pub fn get(&self, path: &str) -> error::Result<Option<CacheEntry>> {
+        match self.entries.get(path)? {
+            Some(val) => CacheEntry::from_bytes(path, &val).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// writes a serialized entry for path right away, dropping any failure recorded for it
+    pub fn insert(&self, path: &str, entry: Vec<u8>) -> error::Result<()> {
+        self.entries.insert(path, entry)?;
+        self.failures.remove(path)?;
+        Ok(())
+    }
+
+    /// writes a batch of new entries (and packed descriptors) in one go
+    pub fn write_batch(&self, batch: CacheBatch) -> error::Result<()> {
+
+        let mut removals = Batch::default();
+        for path in batch.paths.iter() {
+            removals.remove(path.as_bytes());
+        }
+
+        self.store.append(batch.packed)?;
+        self.entries.apply_batch(batch.entries)?;
+        self.failures.apply_batch(removals)?;
+        Ok(())
+    }
+
+    /// makes sure everything written so far is on disk, and makes
+    /// descriptors packed since the cache was opened readable. Compacts
+    /// the block file when replaced images left it mostly dead blocks
+    pub fn flush(&self) -> error::Result<()> {
+        self.db.flush()?;
+        self.store.remap()?;
+        self.store.compact_if_wasteful()?;
+        Ok(())
+    }
+
+    /// the recorded failure for path, if there is one, the file hasn't changed since
+    /// and it was extracted with the same params
+    pub fn load_failure(&self, path: &str, params: &ExtractParams) -> error::Result<Option<Failure>> {
+
+        let val = match self.failures.get(path)? {
+            Some(val) => val,
+            None => return Ok(None)
+        };
+
+        /* unreadable records are treated like no record, the image just gets retried */
+        let record: FailureRecord = match bincode::deserialize(&val) {
+            Ok(record) => record,
+            Err(_) => return Ok(None)
+        };
+
+        match FileStamp::of(path) {
+            Some(stamp) if stamp == record.stamp && record.params == *params => Ok(Some(record.failure)),
+            _ => Ok(None)
+        }
+    }
+
+    /// records a failure that will keep happening until the file (or the params) change
+    pub fn store_failure(&self, failure: &Failure, params: &ExtractParams) -> error::Result<()> {
+
+        let stamp = match FileStamp::of(&failure.path) {
+            Some(stamp) => stamp,
+            None => return Ok(())
+        };
+
+        let record = FailureRecord { failure: failure.clone(), stamp, params: *params };
+        let val = bincode::serialize(&record).map_err(|err| Error::Cache { message: err.to_string() })?;
+        self.failures.insert(&failure.path, val)?;
+
+        Ok(())
+    }
+}
+
+/// new cache entries and packed descriptors waiting to be written together
+#[derive(Default)]
+pub struct CacheBatch {
+    entries: Batch,
+    paths: Vec<String>,
+    packed: Vec<(String, PackedFeatures)>
+}
+
+impl CacheBatch {
+
+    pub fn insert(&mut self, path: String, entry: Vec<u8>) {
+        self.entries.insert(path.as_bytes(), entry);
+        self.paths.push(path);
+    }
+
+    /// packed descriptors for path, for new entries as well as ones cached before they were packed
+    pub fn insert_packed(&mut self, path: String, packed: PackedFeatures) {
+        self.packed.push((path, packed));
+    }
+
+    /// number of writes waiting
+    pub fn len(&self) -> usize {
+        self.paths.len() + self.packed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.packed.is_empty()
+    }
+}
+
 #[derive(Debug)]
 pub struct CacheEntry {
     pub path: String,
     pub keypoints: Vec<MyKeyPoint>,
+    pub descriptors: Vec<Vec<f32>>,
+    pub original_dims: [u32; 2],
+    pub resized_dims: [u32; 2],
+    pub tile_grid_sizes: Vec<u32>,
+    pub tiles: Vec<CacheTile>,
+
+    /// what the features were extracted with
+    pub params: ExtractParams
+}
+
+impl CacheEntry {
+
+    /// deserializes an entry read from the cache db, entries written by older
+    /// versions or damaged on disk come back as CacheCorrupt
+    pub fn from_bytes(path: &str, bytes: &[u8]) -> error::Result<CacheEntry> {
+        bincode::deserialize(bytes).map_err(|err| Error::CacheCorrupt { path: path.to_string(), message: err.to_string() })
+    }
+
+    pub fn to_bytes(&self) -> error::Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|err| Error::Cache { message: err.to_string() })
+    }
+}
+
+/// a failure along with the state of the file when it failed and what it was extracted
+/// with, so the image gets retried once the file or the params change
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+struct FailureRecord {
+    failure: Failure,
+    stamp: FileStamp,
+    params: ExtractParams
+}
+
+/// file size and modification time (seconds, nanoseconds since the epoch)
+#[derive(Debug, PartialEq, serde_derive::Serialize, serde_derive::Deserialize)]
+struct FileStamp {
+    len: u64,
+    modified: (u64, u32)
+}
+
+impl FileStamp {
+    fn of(path: &str) -> Option<FileStamp> {
+        let meta = fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileStamp { len: meta.len(), modified: (modified.as_secs(), modified.subsec_nanos()) })
+    }
+}
+
+/// keypoints and descriptors for one tile of a tiled extraction,
+/// offset and original_dims locate the tile in the full size image
+#[derive(Debug, serde_derive::Serialize, serde_derive::Deserialize)]
+pub struct CacheTile {
+    pub offset: [u32; 2],
+    pub original_dims: [u32; 2],
+    pub resized_dims: [u32; 2],
+    pub keypoints: Vec<MyKeyPoint>,
     pub descriptors: Vec<Vec<f32>>
 }
 
@@ -25,7 +256,7 @@ impl Serialize for CacheEntry {
         S: Serializer
     {
 
-        let num_fields = 1 + self.keypoints.len()*6 + self.descriptors.len();
+        let num_fields = 6 + self.keypoints.len()*6 + self.descriptors.len();
 
         let mut state = serializer.serialize_struct("CacheEntry", num_fields)?;
 
@@ -37,6 +268,17 @@ impl Serialize for CacheEntry {
         let _ = state.serialize_field("keypoints", &self.keypoints);
         let _ = state.serialize_field("descriptors", &mydescriptors);
 
+        /* serialize image dimensions (before and after resizing) */
+        let _ = state.serialize_field("original_dims", &self.original_dims);
+        let _ = state.serialize_field("resized_dims", &self.resized_dims);
+
+        /* serialize tiles (empty unless tiled extraction is enabled) */
+        let _ = state.serialize_field("tile_grid_sizes", &self.tile_grid_sizes);
+        let _ = state.serialize_field("tiles", &self.tiles);
+
+        /* serialize extraction parameters */
+        let _ = state.serialize_field("params", &self.params);
+
         /* finalize  */
         state.end()
     }
@@ -65,7 +307,7 @@ impl<'de> Deserialize<'de> for CacheEntry {
     where
         D: Deserializer<'de>,
     {
-        enum Field { Path, Keypoints, Descriptors }
+        enum Field { Path, Keypoints, Descriptors, OriginalDims, ResizedDims, TileGridSizes, Tiles, Params }
 
         // This part could also be generated independently by:
         //
@@ -83,7 +325,7 @@ impl<'de> Deserialize<'de> for CacheEntry {
                     type Value = Field;
 
                     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                        formatter.write_str("'path' or 'keypoints' or 'descriptors'")
+                        formatter.write_str("'path' or 'keypoints' or 'descriptors' or 'original_dims' or 'resized_dims' or 'tile_grid_sizes' or 'tiles' or 'params'")
                     }
 
                     fn visit_str<E>(self, value: &str) -> Result<Field, E>
@@ -94,6 +336,11 @@ impl<'de> Deserialize<'de> for CacheEntry {
                             "path" => Ok(Field::Path),
                             "keypoints" => Ok(Field::Keypoints),
                             "descriptors" => Ok(Field::Descriptors),
+                            "original_dims" => Ok(Field::OriginalDims),
+                            "resized_dims" => Ok(Field::ResizedDims),
+                            "tile_grid_sizes" => Ok(Field::TileGridSizes),
+                            "tiles" => Ok(Field::Tiles),
+                            "params" => Ok(Field::Params),
                             _ => Err(de::Error::unknown_field(value, FIELDS)),
                         }
                     }
@@ -117,6 +364,11 @@ impl<'de> Deserialize<'de> for CacheEntry {
                 let mut path: Option<String> = None;
                 let mut keypoints: Option<Vec<MyKeyPoint>> = None;
                 let mut descriptors: Option<Vec<Vec<f32>>> = None;
+                let mut original_dims: Option<[u32; 2]> = None;
+                let mut resized_dims: Option<[u32; 2]> = None;
+                let mut tile_grid_sizes: Option<Vec<u32>> = None;
+                let mut tiles: Option<Vec<CacheTile>> = None;
+                let mut params: Option<ExtractParams> = None;
                 
                 while let Some(key) = map.next_key()? {
                     match key {
@@ -138,17 +390,52 @@ impl<'de> Deserialize<'de> for CacheEntry {
                                 return Err(de::Error::duplicate_field("descriptors"));
                             }
                             descriptors = Some(map.next_value()?);
+                        },
+                        Field::OriginalDims => {
+                            if original_dims.is_some() {
+                                return Err(de::Error::duplicate_field("original_dims"));
+                            }
+                            original_dims = Some(map.next_value()?);
+                        },
+                        Field::ResizedDims => {
+                            if resized_dims.is_some() {
+                                return Err(de::Error::duplicate_field("resized_dims"));
+                            }
+                            resized_dims = Some(map.next_value()?);
+                        },
+                        Field::TileGridSizes => {
+                            if tile_grid_sizes.is_some() {
+                                return Err(de::Error::duplicate_field("tile_grid_sizes"));
+                            }
+                            tile_grid_sizes = Some(map.next_value()?);
+                        },
+                        Field::Tiles => {
+                            if tiles.is_some() {
+                                return Err(de::Error::duplicate_field("tiles"));
+                            }
+                            tiles = Some(map.next_value()?);
+                        },
+                        Field::Params => {
+                            if params.is_some() {
+                                return Err(de::Error::duplicate_field("params"));
+                            }
+                            params = Some(map.next_value()?);
                         }
                     }
                 }
                 let path: String = path.ok_or_else(|| de::Error::missing_field("path"))?;
                 let keypoints: Vec<MyKeyPoint> = keypoints.ok_or_else(|| de::Error::missing_field("keypoints"))?;
                 let descriptors: Vec<Vec<f32>> = descriptors.ok_or_else(|| de::Error::missing_field("descriptors"))?;
+                let original_dims: [u32; 2] = original_dims.ok_or_else(|| de::Error::missing_field("original_dims"))?;
+                let resized_dims: [u32; 2] = resized_dims.ok_or_else(|| de::Error::missing_field("resized_dims"))?;
+                let tile_grid_sizes: Vec<u32> = tile_grid_sizes.ok_or_else(|| de::Error::missing_field("tile_grid_sizes"))?;
+                let tiles: Vec<CacheTile> = tiles.ok_or_else(|| de::Error::missing_field("tiles"))?;
+                let params: ExtractParams = params.ok_or_else(|| de::Error::missing_field("params"))?;
 
                 /* "unwrap" KeyPoints from MyKeyPoint wrappers */
                 let descriptors: Vec<Vec<f32>> = descriptors.iter().map(|x| x.clone()).collect();
 
-                Ok(CacheEntry { path, keypoints, descriptors })
+                Ok(CacheEntry { path, keypoints, descriptors, original_dims, resized_dims, tile_grid_sizes, tiles, params })
             }
 
             fn visit_seq<V>(self, mut seq: V) -> Result<CacheEntry, V::Error>
@@ -161,12 +448,22 @@ impl<'de> Deserialize<'de> for CacheEntry {
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
                 let descriptors = seq.next_element()?
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
-                Ok(CacheEntry { path, keypoints, descriptors })
+                let original_dims = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
+                let resized_dims = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(4, &self))?;
+                let tile_grid_sizes = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(5, &self))?;
+                let tiles = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(6, &self))?;
+                let params = seq.next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(7, &self))?;
+                Ok(CacheEntry { path, keypoints, descriptors, original_dims, resized_dims, tile_grid_sizes, tiles, params })
             }
 
         }
 
-        const FIELDS: &'static [&'static str] = &["path", "keypoints", "descriptors"];
+        const FIELDS: &'static [&'static str] = &["path", "keypoints", "descriptors", "original_dims", "resized_dims", "tile_grid_sizes", "tiles", "params"];
         deserializer.deserialize_struct("Duration", FIELDS, CacheEntryVisitor)
     }
 }
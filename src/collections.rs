@@ -0,0 +1,176 @@
+use crate::cache::{Cache, ENTRIES_TREE, FAILURES_TREE};
+use crate::config::{Config, RootSettings, SearchRoot};
+use crate::error::{Error, Result};
+use crate::layers::{flatten, parse_value, set, Source};
+use crate::store::{BLOCKS_FILE, OFFSETS_TREE};
+use crate::validation::{report, validate};
+
+use serde_derive::{Serialize, Deserialize};
+use sled::Tree;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+/// sled tree with the definition of every collection (as toml), by name
+const COLLECTIONS_TREE: &str = "collections";
+
+/// settings a collection can have (key=value, values are toml like --set), anything else comes from the config
+pub const COLLECTION_OPTIONS: [&str; 6] = [
+    "valid_file_extensions",
+    "resize_dimensions",
+    "ratio_test_ratio",
+    "extractor.detector_threshold",
+    "extractor.max_octave_evolution",
+    "extractor.num_sublevels"
+];
+
+/// a named set of search directories with its own settings and its own part of the cache,
+/// searched on its own (or next to other collections, but never mixed with them)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub search_dirs_paths: Vec<SearchRoot>,
+
+    #[serde(flatten)]
+    pub settings: RootSettings
+}
+
+impl Collection {
+
+    /// a collection of dirs (they have to exist, a Usage error otherwise, and are kept as
+    /// absolute paths) with options given as key=value, see COLLECTION_OPTIONS
+    pub fn new(dirs: &[String], options: &[String]) -> Result<Collection> {
+
+        let mut table = Table::new();
+        for option in options.iter() {
+            let (key, raw) = match option.split_once('=') {
+                Some((key, raw)) if COLLECTION_OPTIONS.contains(&key.trim()) => (key.trim(), raw.trim()),
+                _ => return Err(Error::Usage { message: format!("collection option {} isn't key=value with key one of {}", option, COLLECTION_OPTIONS.join(", ")) })
+            };
+            set(&mut table, key, parse_value(raw), Source::Set(option.clone()), &mut BTreeMap::new());
+        }
+        let settings: RootSettings = Value::Table(table).try_into()
+                                        .map_err(|err| Error::Usage { message: format!("invalid collection option: {}", err) })?;
+
+        let mut search_dirs_paths = Vec::new();
+        for dir in dirs.iter() {
+            let path = fs::canonicalize(dir).map_err(|err| Error::Usage { message: format!("search directory {} can't be used: {}", dir, err) })?;
+            search_dirs_paths.push(SearchRoot::Path(path.to_string_lossy().to_string()));
+        }
+
+        Ok(Collection { search_dirs_paths, settings })
+    }
+
+    /// the config the collection is searched with: its search directories and settings over
+    /// everything else in config, a Config error listing every problem with them
+    pub fn config(&self, name: &str, config: &Config) -> Result<Config> {
+
+        let mut config = Config { search_dirs_paths: self.search_dirs_paths.clone(), ..config.clone() };
+        if let Some(extensions) = &self.settings.valid_file_extensions {
+            config.valid_file_extensions = extensions.clone();
+        }
+        if let Some(resize_dimensions) = self.settings.resize_dimensions {
+            config.resize_dimensions = resize_dimensions;
+        }
+        if let Some(ratio_test_ratio) = self.settings.ratio_test_ratio {
+            config.ratio_test_ratio = ratio_test_ratio;
+        }
+        config.extractor = config.extractor.merged_with(&self.settings.extractor);
+
+        report(&format!("collection {}", name), &validate(&mut config))?;
+        Ok(config)
+    }
+
+    /// the settings it has, as key = value
+    pub fn options(&self) -> Vec<String> {
+        let mut options = Vec::new();
+        if let Ok(Value::Table(table)) = Value::try_from(&self.settings) {
+            flatten(&table, "", &mut options);
+        }
+        options.into_iter().map(|(key, value)| format!("{} = {}", key, value)).collect()
+    }
+}
+
+/// names end up in tree and file names, so they're kept simple
+fn check_name(name: &str) -> Result<()> {
+    match !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        true => Ok(()),
+        false => Err(Error::Usage { message: format!("collection name {:?} can only have letters, digits, - and _", name) })
+    }
+}
+
+/// start of the names of the trees (and block file) holding a collection's part of the cache
+fn prefix(name: &str) -> String {
+    format!("collection.{}.", name)
+}
+
+fn registry(cache: &Cache) -> Result<Tree> {
+    Ok(cache.db().open_tree(COLLECTIONS_TREE)?)
+}
+
+fn decode(name: &str, val: &[u8]) -> Result<Collection> {
+    let invalid = |message: String| Error::Cache { message: format!("collection {} can't be read: {}", name, message) };
+    let data = std::str::from_utf8(val).map_err(|err| invalid(err.to_string()))?;
+    toml::from_str(data).map_err(|err| invalid(err.to_string()))
+}
+
+/// adds a collection, a Usage error if there's one by that name already
+pub fn create_collection(cache: &Cache, name: &str, collection: &Collection) -> Result<()> {
+
+    check_name(name)?;
+    let data = Value::try_from(collection).map_err(|err| Error::Cache { message: err.to_string() })?.to_string();
+
+    /* only if it isn't taken, even by another process creating one at the same time */
+    match registry(cache)?.compare_and_swap(name, None as Option<&[u8]>, Some(data.as_bytes()))? {
+        Ok(()) => Ok(()),
+        Err(_) => Err(Error::Usage { message: format!("there's a collection named {} already, drop it first", name) })
+    }
+}
+
+/// the collection by that name, if there is one
+pub fn get_collection(cache: &Cache, name: &str) -> Result<Option<Collection>> {
+    match registry(cache)?.get(name)? {
+        Some(val) => decode(name, &val).map(Some),
+        None => Ok(None)
+    }
+}
+
+/// every collection, by name
+pub fn list_collections(cache: &Cache) -> Result<Vec<(String, Collection)>> {
+    let mut collections = Vec::new();
+    for item in registry(cache)?.iter() {
+        let (key, val) = item?;
+        let name = String::from_utf8_lossy(&key).to_string();
+        let collection = decode(&name, &val)?;
+        collections.push((name, collection));
+    }
+    Ok(collections)
+}
+
+/// a collection along with its part of the cache, a Usage error if there's no collection by that name
+pub fn open_collection(cache: &Cache, name: &str) -> Result<(Collection, Cache)> {
+    let collection = get_collection(cache, name)?
+                        .ok_or_else(|| Error::Usage { message: format!("there's no collection named {}, see collection list", name) })?;
+    Ok((collection, cache.with_prefix(&prefix(name))?))
+}
+
+/// removes a collection and everything cached for it (not its images),
+/// returns whether there was one by that name
+pub fn drop_collection(cache: &Cache, name: &str) -> Result<bool> {
+
+    if registry(cache)?.remove(name)?.is_none() {
+        return Ok(false)
+    }
+
+    let prefix = prefix(name);
+    for tree in [ENTRIES_TREE, FAILURES_TREE, OFFSETS_TREE] {
+        cache.db().drop_tree(format!("{}{}", prefix, tree))?;
+    }
+
+    let blocks = Path::new(cache.path()).join(format!("{}{}", prefix, BLOCKS_FILE));
+    match fs::remove_file(&blocks) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(Error::Cache { message: format!("unable to remove {}: {}", blocks.display(), err) }),
+        _ => Ok(true)
+    }
+}
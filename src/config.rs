@@ -1,13 +1,321 @@
+use crate::region::Region;
+
 use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// everything the search is configured with, fields missing from a config file take their defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub cache_path: String,
-    pub search_dirs_paths: Vec<String>,
+    pub search_dirs_paths: Vec<SearchRoot>,
     pub valid_file_extensions: Vec<String>,
     pub outlier_zscore_thresh: f32,
     pub num_workers: u32,
+    pub num_io_workers: u32,
     pub resize_dimensions: [u32; 2],
     pub ratio_test_ratio: f32,
-    pub print_live_analysis_results: bool
-}
\ No newline at end of file
+    pub print_live_analysis_results: bool,
+    pub match_flipped_horizontal: bool,
+    pub match_flipped_vertical: bool,
+    pub tile_grid_sizes: Vec<u32>,
+    pub extractor: ExtractorOptions,
+    pub detect_file_type_by_content: bool,
+    pub stop_word_fraction: f32,
+    pub query_score: QueryScore,
+    pub scan: ScanOptions,
+    pub results: ResultOptions
+}
+
+/// the values in the bundled config.toml, without any search directories
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            cache_path: ".cache".to_string(),
+            search_dirs_paths: Vec::new(),
+            valid_file_extensions: ["png", "jpg", "jpeg", "tif", "tiff"].iter().map(|ext| ext.to_string()).collect(),
+            outlier_zscore_thresh: 10.0,
+            num_workers: 0,
+            num_io_workers: 0,
+            resize_dimensions: [256, 256],
+            ratio_test_ratio: 0.5,
+            print_live_analysis_results: true,
+            match_flipped_horizontal: false,
+            match_flipped_vertical: false,
+            tile_grid_sizes: Vec::new(),
+            extractor: ExtractorOptions::default(),
+            detect_file_type_by_content: false,
+            stop_word_fraction: 0.0,
+            query_score: QueryScore::Best,
+            scan: ScanOptions::default(),
+            results: ResultOptions::default()
+        }
+    }
+}
+
+impl Config {
+
+    /// settings images are searched with unless their search directory overrides them,
+    /// query images are always extracted with these
+    pub fn settings(&self) -> SearchSettings {
+        SearchSettings {
+            valid_file_extensions: self.valid_file_extensions.clone(),
+            ratio_test_ratio: self.ratio_test_ratio,
+            extract: ExtractParams { resize_dims: self.resize_dimensions, extractor: self.extractor }
+        }
+    }
+}
+
+/// how matches with several query images (views of the same object) make up a search image's score
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryScore {
+    /// matches with whichever query image matched best
+    #[default]
+    Best,
+
+    /// matches with each query image added up
+    Sum
+}
+
+impl fmt::Display for QueryScore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryScore::Best => write!(f, "best"),
+            QueryScore::Sum => write!(f, "sum")
+        }
+    }
+}
+
+/// a search directory, either just its path or a table with the path
+/// and scan options and settings overriding the global ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SearchRoot {
+    Path(String),
+    Table {
+        /* missing in a config file it's empty, which validation reports (with where it was) */
+        #[serde(default)]
+        path: String,
+        #[serde(flatten)]
+        scan: ScanOptions,
+        #[serde(flatten)]
+        settings: RootSettings
+    }
+}
+
+impl SearchRoot {
+
+    pub fn path(&self) -> &String {
+        match self {
+            SearchRoot::Path(path) => path,
+            SearchRoot::Table { path, .. } => path
+        }
+    }
+
+    /// scan options for this root, per-root settings layered over the global ones
+    pub fn scan_options(&self, global: &ScanOptions) -> ScanOptions {
+        match self {
+            SearchRoot::Path(_) => global.clone(),
+            SearchRoot::Table { scan, .. } => global.merged_with(scan)
+        }
+    }
+
+    /// settings this root's images are searched with, its overrides layered over the global ones
+    pub fn settings(&self, config: &Config) -> SearchSettings {
+        let global = config.settings();
+        let overrides = match self {
+            SearchRoot::Path(_) => return global,
+            SearchRoot::Table { settings, .. } => settings
+        };
+        SearchSettings {
+            valid_file_extensions: overrides.valid_file_extensions.clone().unwrap_or(global.valid_file_extensions),
+            ratio_test_ratio: overrides.ratio_test_ratio.unwrap_or(global.ratio_test_ratio),
+            extract: ExtractParams {
+                resize_dims: overrides.resize_dimensions.unwrap_or(global.extract.resize_dims),
+                extractor: global.extract.extractor.merged_with(&overrides.extractor)
+            }
+        }
+    }
+}
+
+impl From<&str> for SearchRoot {
+    fn from(path: &str) -> SearchRoot {
+        SearchRoot::Path(path.to_string())
+    }
+}
+
+/// settings a search directory can override, e.g. for datasets of very different images
+/// (anything not set is taken from the global settings)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RootSettings {
+    pub valid_file_extensions: Option<Vec<String>>,
+    pub resize_dimensions: Option<[u32; 2]>,
+    pub ratio_test_ratio: Option<f32>,
+
+    #[serde(default)]
+    pub extractor: ExtractorOptions
+}
+
+/// akaze feature extractor parameters, anything not set keeps the akaze default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExtractorOptions {
+    /// minimum detector response of a keypoint, lower finds more (and weaker) keypoints, e.g. in low contrast images
+    pub detector_threshold: Option<f64>,
+
+    /// number of octaves (each half the scale of the one before) keypoints are detected in
+    pub max_octave_evolution: Option<u32>,
+
+    /// number of scale levels in each octave
+    pub num_sublevels: Option<u32>
+}
+
+impl ExtractorOptions {
+
+    /// overrides self with any parameters set in other
+    pub fn merged_with(&self, other: &ExtractorOptions) -> ExtractorOptions {
+        ExtractorOptions {
+            detector_threshold: other.detector_threshold.or(self.detector_threshold),
+            max_octave_evolution: other.max_octave_evolution.or(self.max_octave_evolution),
+            num_sublevels: other.num_sublevels.or(self.num_sublevels)
+        }
+    }
+}
+
+/// everything features are extracted with, recorded in cache entries so that
+/// images cached with other parameters get extracted again
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ExtractParams {
+    pub resize_dims: [u32; 2],
+    pub extractor: ExtractorOptions
+}
+
+/// the default resize_dimensions and extractor
+impl Default for ExtractParams {
+    fn default() -> ExtractParams {
+        ExtractParams { resize_dims: [256, 256], extractor: ExtractorOptions::default() }
+    }
+}
+
+/// the settings the images of one search directory are extracted and matched with
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchSettings {
+    pub valid_file_extensions: Vec<String>,
+    pub ratio_test_ratio: f32,
+    pub extract: ExtractParams
+}
+
+/// controls which files are picked up while exploring a search directory (and which parts of them get matched)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanOptions {
+    /// gitignore style globs (relative to the search root), if any are given only matching files are searched
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// gitignore style globs (relative to the search root) for files and directories to leave out
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// how many directory levels below the search root to descend (0 = only the root itself)
+    pub max_depth: Option<usize>,
+
+    /// follow symbolic links (to directories and files), symlink loops are detected and skipped
+    pub follow_symlinks: Option<bool>,
+
+    /// skip directories whose names start with a dot
+    pub skip_hidden: Option<bool>,
+
+    /// files smaller than this many bytes are left out
+    pub min_file_size: Option<u64>,
+
+    /// files larger than this many bytes are left out
+    pub max_file_size: Option<u64>,
+
+    /// regions of the search images (in their pixels) whose keypoints are ignored while matching,
+    /// e.g. a watermark or border every image from a source has
+    #[serde(default)]
+    pub mask: Vec<Region>
+}
+
+impl ScanOptions {
+
+    /// overrides self with any options set in other, include globs
+    /// are replaced while exclude globs and masks add up
+    pub fn merged_with(&self, other: &ScanOptions) -> ScanOptions {
+        ScanOptions {
+            include: match other.include.is_empty() {
+                true => self.include.clone(),
+                false => other.include.clone()
+            },
+            exclude: self.exclude.iter().chain(other.exclude.iter()).cloned().collect(),
+            max_depth: other.max_depth.or(self.max_depth),
+            follow_symlinks: other.follow_symlinks.or(self.follow_symlinks),
+            skip_hidden: other.skip_hidden.or(self.skip_hidden),
+            min_file_size: other.min_file_size.or(self.min_file_size),
+            max_file_size: other.max_file_size.or(self.max_file_size),
+            mask: self.mask.iter().chain(other.mask.iter()).cloned().collect()
+        }
+    }
+}
+
+/// which of the matches (images over outlier_zscore_thresh) are reported, and in what order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResultOptions {
+    /// report at most this many matches (after sorting)
+    pub top_k: Option<usize>,
+
+    /// matches with fewer keypoint matches than this are left out
+    pub min_matches: Option<u32>,
+
+    /// gitignore style globs matched against the (canonical) path of each match,
+    /// if any are given only matches of at least one of them are reported
+    #[serde(default)]
+    pub paths: Vec<String>,
+
+    #[serde(default)]
+    pub sort_by: SortBy
+}
+
+/// order matches are reported in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    /// highest z-score (most matches) first
+    #[default]
+    Score,
+
+    Path,
+
+    /// most recently modified first
+    Mtime,
+
+    /// largest file first
+    Size
+}
+
+impl fmt::Display for SortBy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SortBy::Score => write!(f, "score"),
+            SortBy::Path => write!(f, "path"),
+            SortBy::Mtime => write!(f, "mtime"),
+            SortBy::Size => write!(f, "size")
+        }
+    }
+}
+
+/// "score", "path", "mtime" or "size", as given to --sort
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortBy, String> {
+        match s {
+            "score" => Ok(SortBy::Score),
+            "path" => Ok(SortBy::Path),
+            "mtime" => Ok(SortBy::Mtime),
+            "size" => Ok(SortBy::Size),
+            _ => Err(format!("expected score, path, mtime or size, got {}", s))
+        }
+    }
+}
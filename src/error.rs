@@ -0,0 +1,206 @@
+use image::ImageError;
+use serde_derive::{Serialize, Deserialize};
+use std::any::Any;
+use std::fmt;
+use std::io;
+
+/// exit code when the search ran and every file could be read
+pub const EXIT_OK: u8 = 0;
+
+/// exit code when the search ran but some files or directories couldn't be read
+pub const EXIT_PARTIAL: u8 = 1;
+
+/// everything that can go wrong in the search pipeline
+#[derive(Debug)]
+pub enum Error {
+    /// config file missing, unparseable or with invalid values
+    Config { message: String },
+
+    /// the program was run in a way that can't work, e.g. no query image without a gui
+    Usage { message: String },
+
+    /// file couldn't be read
+    Io { path: String, source: io::Error },
+
+    /// an output file (e.g. --list-file) couldn't be written
+    Output { path: String, source: io::Error },
+
+    /// file was read but isn't a decodable image
+    Decode { path: String, source: ImageError },
+
+    /// the cache database itself failed (open, read or write)
+    Cache { message: String },
+
+    /// a cache entry exists but can't be deserialized
+    CacheCorrupt { path: String, message: String },
+
+    /// the image decoder panicked on this file
+    DecodePanic { path: String, message: String },
+
+    /// feature extraction failed (e.g. the extractor panicked)
+    Extractor { path: String, message: String },
+
+    /// the image decoded but no keypoints were found in it
+    NoKeypoints { path: String },
+
+    /// the image failed on an earlier run and hasn't changed since
+    PreviousFailure { failure: Failure },
+
+    /// the search itself (not a single image) panicked, e.g. while collecting results
+    Internal { message: String }
+}
+
+/// shorthand for results using the crate error
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+
+    /// process exit code for a run that stopped on this error:
+    /// 2 config or usage, 3 cache, 4 the query image couldn't be read or processed, 5 internal error,
+    /// 6 an output file couldn't be written
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config { .. } | Error::Usage { .. } => 2,
+            Error::Cache { .. } | Error::CacheCorrupt { .. } => 3,
+            Error::Io { .. } | Error::Decode { .. } | Error::DecodePanic { .. } | Error::Extractor { .. }
+                | Error::NoKeypoints { .. } | Error::PreviousFailure { .. } => 4,
+            Error::Internal { .. } => 5,
+            Error::Output { .. } => 6
+        }
+    }
+
+    /// what kind of per-image failure this is, None for errors that aren't about a single image
+    pub fn failure_kind(&self) -> Option<FailureKind> {
+        match self {
+            Error::Io { .. } => Some(FailureKind::Io),
+            Error::Decode { source: ImageError::Unsupported(_), .. } => Some(FailureKind::Unsupported),
+            Error::Decode { .. } => Some(FailureKind::Corrupt),
+            Error::DecodePanic { .. } => Some(FailureKind::DecodePanic),
+            Error::Extractor { .. } => Some(FailureKind::ExtractorPanic),
+            Error::NoKeypoints { .. } => Some(FailureKind::NoKeypoints),
+            Error::PreviousFailure { failure } => Some(failure.kind),
+            Error::Config { .. } | Error::Usage { .. } | Error::Cache { .. } | Error::CacheCorrupt { .. } | Error::Internal { .. }
+                | Error::Output { .. } => None
+        }
+    }
+
+    /// io errors surfaced by the image crate are reported as io errors, except running out
+    /// of data (a truncated file), which is a decode error like everything else
+    pub fn from_image(path: &str, err: ImageError) -> Error {
+        match err {
+            ImageError::IoError(source) if source.kind() != io::ErrorKind::UnexpectedEof => Error::Io { path: path.to_string(), source },
+            source => Error::Decode { path: path.to_string(), source }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config { message } => write!(f, "config error: {}", message),
+            Error::Usage { message } => write!(f, "{}", message),
+            Error::Io { path, source } => write!(f, "unable to read {}: {}", path, source),
+            Error::Output { path, source } => write!(f, "unable to write {}: {}", path, source),
+            Error::Decode { path, source } => write!(f, "unable to decode {}: {}", path, source),
+            Error::Cache { message } => write!(f, "cache error: {}", message),
+            Error::CacheCorrupt { path, message } => write!(f, "corrupt cache entry for {}: {}", path, message),
+            Error::DecodePanic { path, message } => write!(f, "image decoder panicked on {}: {}", path, message),
+            Error::Extractor { path, message } => write!(f, "feature extraction failed for {}: {}", path, message),
+            Error::NoKeypoints { path } => write!(f, "no keypoints found in {}", path),
+            Error::PreviousFailure { failure } => write!(f, "{} (failed on a previous run, unchanged since)", failure),
+            Error::Internal { message } => write!(f, "internal error: {}", message)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Output { source, .. } => Some(source),
+            Error::Decode { source, .. } => Some(source),
+            _ => None
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Error {
+        Error::Cache { message: err.to_string() }
+    }
+}
+
+/// why an image couldn't be processed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FailureKind {
+    Unsupported,
+    Corrupt,
+    Io,
+    NoKeypoints,
+    DecodePanic,
+    ExtractorPanic,
+    MatchingPanic
+}
+
+impl FailureKind {
+
+    /// failures that will happen again as long as the file doesn't change,
+    /// io errors (permissions, files being written) may well go away on their own
+    pub fn is_persistent(&self) -> bool {
+        *self != FailureKind::Io
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureKind::Unsupported => write!(f, "unsupported format"),
+            FailureKind::Corrupt => write!(f, "truncated or corrupt"),
+            FailureKind::Io => write!(f, "io error"),
+            FailureKind::NoKeypoints => write!(f, "no keypoints"),
+            FailureKind::DecodePanic => write!(f, "decoder panicked"),
+            FailureKind::ExtractorPanic => write!(f, "extractor panicked"),
+            FailureKind::MatchingPanic => write!(f, "matching panicked")
+        }
+    }
+}
+
+/// an image that couldn't be processed and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Failure {
+    pub path: String,
+    pub kind: FailureKind,
+    pub message: String
+}
+
+impl Failure {
+
+    /// None if err isn't a per-image failure (e.g. the cache is broken)
+    pub fn from_error(path: &str, err: &Error) -> Option<Failure> {
+
+        let message = match err {
+            Error::Io { source, .. } => source.to_string(),
+            Error::Decode { source, .. } => source.to_string(),
+            Error::DecodePanic { message, .. } | Error::Extractor { message, .. } => message.clone(),
+            Error::NoKeypoints { .. } => "no keypoints detected".to_string(),
+            Error::PreviousFailure { failure } => return Some(failure.clone()),
+            _ => return None
+        };
+
+        Some(Failure { path: path.to_string(), kind: err.failure_kind()?, message })
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.path, self.kind, self.message)
+    }
+}
+
+/// the message a panic was started with, if it was a string
+pub fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        _ => "unknown panic".to_string()
+    }
+}
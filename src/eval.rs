@@ -0,0 +1,312 @@
+use crate::args::EvalArgs;
+use crate::step;
+
+use local_reverse_image_search::augment::Augmentation;
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::Config;
+use local_reverse_image_search::error::{Error, Result, EXIT_OK, EXIT_PARTIAL};
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_and_cache, extract_query, find_outliers, ImgInfo};
+use local_reverse_image_search::utils::{find_image_files, open_image, XorShift};
+
+use console::style;
+use serde_derive::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// steps of an eval run, loading the config is the first
+pub const EVAL_STEPS: usize = 5;
+
+/// query images and the search images each one is expected to match
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroundTruth {
+    pub queries: Vec<GroundTruthEntry>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroundTruthEntry {
+    pub query: String,
+    pub matches: Vec<String>
+}
+
+/// search quality of a single query
+struct Scores {
+    precision_at_k: f32,
+
+    /// best precision@k possible with this many expected matches, below 1 when there are fewer
+    /// than k (e.g. 1/k for synthetic queries, which only expect their original)
+    max_precision_at_k: f32,
+
+    /// fraction of the expected matches in the top k
+    recall_at_k: f32,
+
+    /// fraction of the expected matches reported (outliers)
+    recall: f32,
+
+    /// 1 / rank of the first expected match, 0 if none was ranked
+    reciprocal_rank: f32,
+    average_precision: f32
+}
+
+/// search quality and timing for a single query
+struct QueryResult {
+    query: String,
+    scores: Scores,
+    extraction_time: Duration,
+    matching_time: Duration
+}
+
+/// canonical form of a path so ground truth and discovered paths compare equal
+fn normalize(path: &str) -> String {
+    match fs::canonicalize(path) {
+        Ok(p) => p.to_string_lossy().to_string(),
+        Err(_) => path.to_string()
+    }
+}
+
+fn load_ground_truth(filepath: &String) -> Result<GroundTruth> {
+
+    let data = fs::read_to_string(filepath)
+                    .map_err(|err| Error::Config { message: format!("unable to read ground truth file {}: {}", filepath, err) })?;
+
+    toml::from_str(&data).map_err(|err| Error::Config { message: format!("unable to parse ground truth file {}: {}", filepath, err) })
+}
+
+/// samples search images, writes an augmented copy of each to the eval dir as a query
+/// and records the original as its expected match, the ground truth file is saved
+/// next to the queries so the same set can be re-run with --ground-truth-path
+fn build_synthetic_ground_truth(img_paths: &Vec<String>, args: &EvalArgs) -> GroundTruth {
+
+    let out_dir = Path::new(&args.eval_dir).join(format!("seed_{}", args.seed));
+    if let Err(err) = fs::create_dir_all(&out_dir) {
+        println!("{}: unable to create {}\nerror: {}", style("ERROR").bold().bright().red(), out_dir.display(), err);
+        return GroundTruth { queries: Vec::new() }
+    }
+
+    let mut rng = XorShift::new(args.seed);
+    let mut queries: Vec<GroundTruthEntry> = Vec::new();
+    let mut used: HashSet<usize> = HashSet::new();
+    let num_queries = args.num_queries.min(img_paths.len());
+
+    /* give up after a while if lots of images fail to open */
+    let mut attempts = 0;
+    while queries.len() < num_queries && attempts < num_queries * 4 {
+        attempts += 1;
+
+        let ind = rng.below(img_paths.len());
+        if !used.insert(ind) {
+            continue
+        }
+        let src = &img_paths[ind];
+
+        let img = match open_image(src) {
+            Ok(img) => img,
+            Err(_) => continue
+        };
+
+        /* cycle through augmentations so each gets roughly equal coverage */
+        let aug = Augmentation::ALL[queries.len() % Augmentation::ALL.len()];
+        let stem = Path::new(src).file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let query_path = out_dir.join(format!("{:03}_{}_{}.png", queries.len(), aug, stem));
+
+        if aug.apply(&img, &mut rng).save(&query_path).is_err() {
+            continue
+        }
+
+        queries.push(GroundTruthEntry {
+            query: query_path.to_string_lossy().to_string(),
+            matches: vec![src.clone()]
+        });
+    }
+
+    let gt = GroundTruth { queries };
+
+    let gt_path = out_dir.join("ground_truth.toml");
+    match toml::to_string(&gt).map(|s| fs::write(&gt_path, s)) {
+        Ok(Ok(_)) => println!("wrote {} synthetic queries, ground truth saved to {}", gt.queries.len(), style(gt_path.display()).bold()),
+        _ => println!("{}: unable to save ground truth to {}", style("WARNING").bold().yellow(), gt_path.display())
+    }
+
+    gt
+}
+
+/// precision and recall@k, recall of the reported (outlier) matches, and reciprocal rank and
+/// average precision over the full ranking
+fn score_query(ranked: &Vec<ImgInfo>, reported: &Vec<(f32, &ImgInfo)>, expected: &HashSet<String>, k: usize) -> Scores {
+
+    let k = k.max(1);
+    if expected.is_empty() {
+        return Scores { precision_at_k: 0.0, max_precision_at_k: 0.0, recall_at_k: 0.0, recall: 0.0, reciprocal_rank: 0.0, average_precision: 0.0 }
+    }
+
+    let ranked_paths: Vec<String> = ranked.iter().map(|info| normalize(&info.path)).collect();
+
+    let hits_at_k = ranked_paths.iter().take(k).filter(|p| expected.contains(*p)).count();
+    let precision_at_k = hits_at_k as f32 / k as f32;
+    let max_precision_at_k = expected.len().min(k) as f32 / k as f32;
+    let recall_at_k = hits_at_k as f32 / expected.len() as f32;
+
+    let reported_hits = reported.iter().filter(|(_, info)| expected.contains(&normalize(&info.path))).count();
+    let recall = reported_hits as f32 / expected.len() as f32;
+
+    let reciprocal_rank = match ranked_paths.iter().position(|p| expected.contains(p)) {
+        Some(rank) => 1.0 / (rank + 1) as f32,
+        None => 0.0
+    };
+
+    let mut hits = 0;
+    let mut sum_precision = 0.0;
+    for (rank, path) in ranked_paths.iter().enumerate() {
+        if expected.contains(path) {
+            hits += 1;
+            sum_precision += hits as f32 / (rank + 1) as f32;
+        }
+    }
+    let average_precision = sum_precision / expected.len() as f32;
+
+    Scores { precision_at_k, max_precision_at_k, recall_at_k, recall, reciprocal_rank, average_precision }
+}
+
+/// runs the evaluation, returns the exit code (see error.rs)
+pub fn run_eval(config: &Config, args: &EvalArgs) -> Result<u8> {
+
+    /* per-query searches are quiet, only the summary lines matter here */
+    let mut config = config.clone();
+    config.print_live_analysis_results = false;
+
+    let cache = Cache::open(&config.cache_path)?;
+
+    /* get all image file paths in search directories */
+    println!("\n{} exploring {} search directories...", step(2, EVAL_STEPS), &config.search_dirs_paths.len());
+    let discovery = find_image_files(&config, &config.search_dirs_paths)?;
+    discovery.print_summary();
+    let discovery_errors = discovery.errors.len();
+    let img_paths = discovery.img_paths;
+
+    if img_paths.len() == 0 {
+        println!("{}: no images found in search paths", style("ERROR").bold().bright().red());
+        return Ok(EXIT_PARTIAL)
+    }
+
+    /* load or build ground truth */
+    println!("\n\n{} loading ground truth...", step(3, EVAL_STEPS));
+    let ground_truth = match &args.ground_truth_path {
+        Some(path) => load_ground_truth(path)?,
+        None => build_synthetic_ground_truth(&img_paths, args)
+    };
+
+    if ground_truth.queries.len() == 0 {
+        return Err(Error::Config { message: "ground truth has no queries".to_string() })
+    }
+
+    /* warn about expected matches that aren't in the search dirs, they still count against recall */
+    let discovered: HashSet<String> = img_paths.iter().map(|p| normalize(p)).collect();
+    for entry in ground_truth.queries.iter() {
+        for m in entry.matches.iter().filter(|m| !discovered.contains(&normalize(m))) {
+            println!("{}: expected match {} for query {} is not in the search dirs", style("WARNING").bold().yellow(), style(m).bold(), entry.query);
+        }
+    }
+
+    /* extract (and cache) every search image up front so per-query matching
+       times aren't skewed by first-time extraction */
+    println!("\n{} warming cache...", step(4, EVAL_STEPS));
+    let timer = Instant::now();
+    let failures = extract_and_cache(&cache, &config, &img_paths)?;
+    println!("extracted {} images in {:?} ({} failed)", img_paths.len(), timer.elapsed(), failures.len());
+
+    /* run every query */
+    println!("\n{} running {} queries...", step(5, EVAL_STEPS), ground_truth.queries.len());
+    let mut results: Vec<QueryResult> = Vec::new();
+
+    for entry in ground_truth.queries.iter() {
+
+        let timer = Instant::now();
+        let query = match extract_query(&cache, &config, &entry.query) {
+            Ok(query) => query,
+            Err(err) => {
+                println!("{}: {}, skipping query", style("ERROR").bold().bright().red(), err);
+                continue
+            }
+        };
+        let extraction_time = timer.elapsed();
+
+        let timer = Instant::now();
+        let (info, _) = calculate_similarities(&cache, &config, &query, img_paths.clone())?;
+        let matching_time = timer.elapsed();
+
+        /* rank by number of matches, leaving out the query itself if it's in the search dirs */
+        let query_norm = normalize(&entry.query);
+        let mut ranked: Vec<ImgInfo> = info.into_iter()
+                                            .filter(|info| normalize(&info.path) != query_norm)
+                                            .collect();
+        ranked.sort_by(|a, b| b.num_matches.cmp(&a.num_matches).then_with(|| a.path.cmp(&b.path)));
+
+        let (_, _, reported) = find_outliers(&ranked, config.outlier_zscore_thresh);
+        let expected: HashSet<String> = entry.matches.iter().map(|m| normalize(m)).collect();
+        let scores = score_query(&ranked, &reported, &expected, args.top_k);
+
+        results.push(QueryResult {
+            query: entry.query.clone(),
+            scores,
+            extraction_time,
+            matching_time
+        });
+    }
+
+    if results.len() == 0 {
+        println!("{}: no queries could be evaluated", style("ERROR").bold().bright().red());
+        return Ok(EXIT_PARTIAL)
+    }
+
+    /* print per-query results */
+    let topstr = format!("----{} QUERIES----", style(results.len()).bold());
+    println!("\n{}", topstr);
+    for r in results.iter() {
+        println!("{}: {:.2}, {}: {:.2}, {}: {:.2}, {}: {:.2}, {}: {:.2}, {}: {:?}, {}: {:?} <- {}",
+                                    style(format!("p@{}", args.top_k)).bold().bright(), r.scores.precision_at_k,
+                                    style(format!("r@{}", args.top_k)).bold().bright(), r.scores.recall_at_k,
+                                    style("recall").bold().bright(), r.scores.recall,
+                                    style("RR").bold().bright(), r.scores.reciprocal_rank,
+                                    style("AP").bold().bright(), r.scores.average_precision,
+                                    style("extract").bold().bright(), r.extraction_time,
+                                    style("match").bold().bright(), r.matching_time,
+                                    style(&r.query).bold().color256(42));
+    }
+    println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
+
+    /* print summary */
+    let n = results.len() as f32;
+    let mean = |score: fn(&Scores) -> f32| results.iter().map(|r| score(&r.scores)).sum::<f32>() / n;
+    let mean_precision_at_k = mean(|s| s.precision_at_k);
+    let mean_max_precision_at_k = mean(|s| s.max_precision_at_k);
+    let mean_recall_at_k = mean(|s| s.recall_at_k);
+    let mean_recall = mean(|s| s.recall);
+    let mrr = mean(|s| s.reciprocal_rank);
+    let map = mean(|s| s.average_precision);
+    let mean_extraction: Duration = results.iter().map(|r| r.extraction_time).sum::<Duration>() / results.len() as u32;
+    let mean_matching: Duration = results.iter().map(|r| r.matching_time).sum::<Duration>() / results.len() as u32;
+
+    println!("\n{}", style("SUMMARY").bold());
+    /* with fewer expected matches than k even a perfect ranking can't fill the top k */
+    match mean_max_precision_at_k < 1.0 {
+        true => println!("{:>16}: {:.3} (at most {:.3} with this ground truth, see recall@{} and MRR)",
+                                    format!("precision@{}", args.top_k), mean_precision_at_k, mean_max_precision_at_k, args.top_k),
+        false => println!("{:>16}: {:.3}", format!("precision@{}", args.top_k), mean_precision_at_k)
+    }
+    println!("{:>16}: {:.3}", format!("recall@{}", args.top_k), mean_recall_at_k);
+    println!("{:>16}: {:.3}", "recall", mean_recall);
+    println!("{:>16}: {:.3}", "MRR", mrr);
+    println!("{:>16}: {:.3}", "mAP", map);
+    println!("{:>16}: {:?}", "mean extraction", mean_extraction);
+    println!("{:>16}: {:?}", "mean matching", mean_matching);
+    println!("\nratio_test_ratio: {}, outlier_zscore_thresh: {}, resize_dimensions: {:?}",
+                                    config.ratio_test_ratio, config.outlier_zscore_thresh, config.resize_dimensions);
+
+    /* some queries skipped or search images unreadable */
+    match results.len() < ground_truth.queries.len() || failures.len() > 0 || discovery_errors > 0 {
+        true => Ok(EXIT_PARTIAL),
+        false => Ok(EXIT_OK)
+    }
+}
@@ -1,30 +1,49 @@
-use crate::cache::{CacheEntry, MyKeyPoint};
-use crate::config::Config;
+use crate::cache::{Cache, CacheBatch, CacheEntry, CacheTile, MyKeyPoint};
+use crate::config::{Config, ExtractParams, QueryScore, SearchSettings, SortBy};
+use crate::error::{panic_message, Error, Failure, FailureKind, Result};
+use crate::hamming::{self, Kernel};
+use crate::localization::{Location, localize};
+use crate::region::Region;
+use crate::store::{Block, PackedFeatures, PackedRegion, DESCRIPTOR_BYTES};
+use crate::utils::{build_glob_matcher, decode_image, open_image, Semaphore};
 
 // use std::path::Path;
 // use cv::{feature::akaze::Akaze, KeyPoint, BitArray};
 // use cv::feature::akaze
 use akaze::{Akaze, KeyPoint};
 use serde::{Serialize, Deserialize};
-use std::sync::{Arc, Mutex};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::sync_channel;
 use std::thread;
 use bitarray::BitArray;
 // use image::dynimage::DynamicImage;
 use kdam::{tqdm, BarExt};
 use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
 // use std::collections::HashMap;
 use std::fmt;
 use console::style;
 use num_cpus;
-use sled::Db;
-use bincode;
 use kdtree::KdTree;
 use kdtree::distance::squared_euclidean;
+use statistical::{mean, standard_deviation};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ImgInfo {
     pub path: String,
-    pub num_matches: u32
+    pub num_matches: u32,
+    pub location: Option<Location>,
+    pub orientation: Orientation,
+
+    /// index of the query image that matched best (see QueryView::source)
+    #[serde(default)]
+    pub source: usize
 }
 
 impl fmt::Display for ImgInfo {
@@ -33,74 +52,439 @@ impl fmt::Display for ImgInfo {
     }
 }
 
+/// which copy of the query image produced a match
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Orientation {
+    Original,
+    FlippedHorizontal,
+    FlippedVertical
+}
 
-pub fn extract_single(cache: Arc<Mutex<Db>>, resize_dims: [u32; 2], path: &String) -> Option<(Vec<KeyPoint>, Vec<BitArray<64>>, bool)> {
+impl fmt::Display for Orientation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Orientation::Original => write!(f, "original"),
+            Orientation::FlippedHorizontal => write!(f, "flipped horizontally"),
+            Orientation::FlippedVertical => write!(f, "flipped vertically")
+        }
+    }
+}
 
-    let cache_mguard = cache.lock().unwrap();
-    let res = cache_mguard.get(path);
-    drop(cache_mguard);
- 
-    match res {
+/// features of a query image in one orientation
+#[derive(Debug, Clone)]
+pub struct QueryView {
+    pub orientation: Orientation,
+    pub features: ImgFeatures,
 
-        Ok(res) => match res {
+    /// which of the query images (of the same object) this is a view of, 0 unless there are several
+    pub source: usize,
 
-            Some(val) => {
+    /// bounding rectangle of the region of interest in this view's original pixel coordinates
+    /// --> [x, y, width, height], None if the whole image is searched for
+    pub roi: Option<[f32; 4]>
+}
 
-                // println!("{}: {}", style("importing from cache").bold().yellow(), path.clone());
+impl QueryView {
 
-                let ce: CacheEntry = bincode::deserialize(&val).unwrap();
-                let mykeypoints: Vec<KeyPoint> = ce.keypoints.iter().map(|kp| kp.0).collect();
-                let mydescriptors: Vec<BitArray<64>> = ce.descriptors.iter().map(|d| {
-                    let mut arr: [u8; 64] = [0 as u8; 64];
-                    
-                    for (i, byte) in d.iter().enumerate() {
-                        arr[i] = byte.clone() as u8;
-                    }
-                    
-                    BitArray::new(arr)
-                }).collect();
-                Some((mykeypoints, mydescriptors, true))
-            },
-
-            None => {
-                /* make new feature extractor */
-                let akaze = Akaze::default();
-            
-                /* extract keypoints and descriptors */
-                let [nwidth, nheight] = resize_dims;
-                let filter = FilterType::Nearest;
-                let img = match image::open(&path) {
-
-                    Ok(img) => img.resize(nwidth, nheight, filter),
-
-                    Err(_) => {
-                        // println!("\n------------------");
-                        // println!("{}: unable to open {}\n\n{}", style("ERROR").bold().bright().red(), style(path).bold().bright(), err);
-                        // println!("------------------\n");
-                        return None
-                    }
-                };
-            
-                /* return extracted info */
-                let (keypoints, descriptors) = akaze.extract(&img);
-                let mykeypoints: Vec<MyKeyPoint> = keypoints.iter().map(|kp| MyKeyPoint(*kp)).collect();
-                let mydescriptors: Vec<Vec<f32>> = descriptors.iter().map(|x| bitarray_to_floatvec(x)).collect();
-                let ce: CacheEntry = CacheEntry{path: path.to_string(), keypoints: mykeypoints, descriptors: mydescriptors};
-                let ce_ser: Vec<u8> = bincode::serialize(&ce).unwrap();
-
-                /* add to database */
-                let cache_mguard = cache.lock().unwrap();
-                let _ = cache_mguard.insert(path, ce_ser);
-                drop(cache_mguard);
-
-                // println!("{}: {}", style("added to cache ").bold().green(), path.clone());
-
-                /* return */
-                Some((keypoints, descriptors, false))
-            }
+    /// drops keypoints (and their descriptors) outside of regions, which are given in the
+    /// coordinates of the original query image (not of this view, which may be flipped),
+    /// and keeps their bounding rectangle so localization outlines just the regions
+    pub fn restrict_to(&mut self, regions: &[Region]) {
+
+        let [w, h] = self.features.original_dims;
+        let orientation = self.orientation;
+
+        self.features.retain_keypoints(|[x, y]| {
+            let point = match orientation {
+                Orientation::Original => [x, y],
+                Orientation::FlippedHorizontal => [w as f32 - x, y],
+                Orientation::FlippedVertical => [x, h as f32 - y]
+            };
+            regions.iter().any(|region| region.contains(point))
+        });
+
+        /* union of the regions' bounds, clipped to the image */
+        let (w, h) = (w as f32, h as f32);
+        let bounds = regions.iter().map(|region| region.bounds());
+        let x0 = bounds.clone().map(|[x, _, _, _]| x).fold(f32::INFINITY, f32::min).max(0.0);
+        let y0 = bounds.clone().map(|[_, y, _, _]| y).fold(f32::INFINITY, f32::min).max(0.0);
+        let x1 = bounds.clone().map(|[x, _, bw, _]| x + bw).fold(f32::NEG_INFINITY, f32::max).min(w);
+        let y1 = bounds.map(|[_, y, _, bh]| y + bh).fold(f32::NEG_INFINITY, f32::max).min(h);
+
+        self.roi = match x1 > x0 && y1 > y0 {
+            true => Some(match orientation {
+                Orientation::Original => [x0, y0, x1 - x0, y1 - y0],
+                Orientation::FlippedHorizontal => [w - x1, y0, x1 - x0, y1 - y0],
+                Orientation::FlippedVertical => [x0, h - y1, x1 - x0, y1 - y0]
+            }),
+            false => None
+        };
+    }
+}
+
+/// keypoints and descriptors extracted from a single image (or one tile of it),
+/// along with the region's dimensions before and after resizing
+#[derive(Debug, Clone)]
+pub struct ImgFeatures {
+    pub keypoints: Vec<KeyPoint>,
+    pub descriptors: Vec<BitArray<64>>,
+    pub original_dims: [u32; 2],
+    pub resized_dims: [u32; 2],
+
+    /// top left corner of this region in the full size image, [0, 0] unless this is a tile
+    pub offset: [u32; 2],
+
+    /// higher resolution tiles of the image, empty unless tiled extraction is enabled
+    pub tiles: Vec<ImgFeatures>
+}
+
+impl ImgFeatures {
+
+    /// factors mapping resized image coordinates back to original image coordinates
+    pub fn scale(&self) -> [f32; 2] {
+        let [ow, oh] = self.original_dims;
+        let [rw, rh] = self.resized_dims;
+        [ow as f32 / rw.max(1) as f32, oh as f32 / rh.max(1) as f32]
+    }
+
+    /// the whole image followed by each of its tiles
+    pub fn regions(&self) -> impl Iterator<Item = &ImgFeatures> {
+        std::iter::once(self).chain(self.tiles.iter())
+    }
+
+    /// position of one of this region's keypoints in the full size image
+    pub fn original_point(&self, kp: &KeyPoint) -> [f32; 2] {
+        let [sx, sy] = self.scale();
+        let [ox, oy] = self.offset;
+        [kp.point.0 * sx + ox as f32, kp.point.1 * sy + oy as f32]
+    }
+
+    /// keeps the keypoints (and their descriptors) for which keep is true, it gets each
+    /// keypoint's position mapped back through the resize to original image coordinates,
+    /// tiles are left alone
+    pub fn retain_keypoints<F>(&mut self, mut keep: F)
+    where
+        F: FnMut([f32; 2]) -> bool
+    {
+        let kept: Vec<bool> = self.keypoints.iter().map(|kp| keep(self.original_point(kp))).collect();
+
+        let mut kept_keypoints = kept.iter();
+        self.keypoints.retain(|_| *kept_keypoints.next().unwrap());
+        let mut kept_descriptors = kept.iter();
+        self.descriptors.retain(|_| *kept_descriptors.next().unwrap());
+    }
+
+    fn from_cache(ce: &CacheEntry) -> ImgFeatures {
+
+        let tiles: Vec<ImgFeatures> = ce.tiles.iter().map(|t| ImgFeatures {
+            keypoints: t.keypoints.iter().map(|kp| kp.0).collect(),
+            descriptors: t.descriptors.iter().map(|d| floatvec_to_bitarray(d)).collect(),
+            original_dims: t.original_dims,
+            resized_dims: t.resized_dims,
+            offset: t.offset,
+            tiles: Vec::new()
+        }).collect();
+
+        ImgFeatures {
+            keypoints: ce.keypoints.iter().map(|kp| kp.0).collect(),
+            descriptors: ce.descriptors.iter().map(|d| floatvec_to_bitarray(d)).collect(),
+            original_dims: ce.original_dims,
+            resized_dims: ce.resized_dims,
+            offset: [0, 0],
+            tiles
+        }
+    }
+
+    fn to_cache(&self, path: &String, params: &ExtractParams, tile_grid_sizes: &Vec<u32>) -> CacheEntry {
+
+        let tiles: Vec<CacheTile> = self.tiles.iter().map(|t| CacheTile {
+            offset: t.offset,
+            original_dims: t.original_dims,
+            resized_dims: t.resized_dims,
+            keypoints: t.keypoints.iter().map(|kp| MyKeyPoint(*kp)).collect(),
+            descriptors: t.descriptors.iter().map(|x| bitarray_to_floatvec(x)).collect()
+        }).collect();
+
+        CacheEntry {
+            path: path.to_string(),
+            keypoints: self.keypoints.iter().map(|kp| MyKeyPoint(*kp)).collect(),
+            descriptors: self.descriptors.iter().map(|x| bitarray_to_floatvec(x)).collect(),
+            original_dims: self.original_dims,
+            resized_dims: self.resized_dims,
+            tile_grid_sizes: tile_grid_sizes.clone(),
+            tiles,
+            params: *params
+        }
+    }
+
+    /// the packed form kept in the descriptor store, the whole image followed by its tiles
+    pub fn to_packed(&self, params: &ExtractParams, tile_grid_sizes: &Vec<u32>) -> PackedFeatures {
+
+        let mut packed = PackedFeatures::new(tile_grid_sizes, params);
+        for region in self.regions() {
+            packed.push_region(
+                region.original_dims,
+                region.resized_dims,
+                region.offset,
+                region.keypoints.iter().map(|kp| MyKeyPoint(*kp)).collect(),
+                region.descriptors.iter().map(bitarray_to_block)
+            );
+        }
+
+        packed
+    }
+
+    /// a packed region without its descriptors, enough to localize matches in it
+    fn from_packed(region: &PackedRegion) -> ImgFeatures {
+        ImgFeatures {
+            keypoints: region.keypoints.iter().map(|kp| kp.0).collect(),
+            descriptors: Vec::new(),
+            original_dims: region.original_dims,
+            resized_dims: region.resized_dims,
+            offset: region.offset,
+            tiles: Vec::new()
+        }
+    }
+}
+
+/// whether cached features can be used when tile_grid_sizes are asked for
+/// (none asked for means whatever was cached is fine)
+fn has_tiles(cached: &Vec<u32>, tile_grid_sizes: &Vec<u32>) -> bool {
+    tile_grid_sizes.is_empty() || cached == tile_grid_sizes
+}
+
+/// whether cached features were extracted the way they're asked for now
+fn is_usable(cached_params: &ExtractParams, cached_tiles: &Vec<u32>, params: &ExtractParams, tile_grid_sizes: &Vec<u32>) -> bool {
+    cached_params == params && has_tiles(cached_tiles, tile_grid_sizes)
+}
+
+
+/// gets keypoints and descriptors for the image at path, from the cache if they were extracted
+/// with the same params, tile_grid_sizes enables tiled extraction (see extract_from_image)
+pub fn extract_single(cache: &Cache, params: &ExtractParams, tile_grid_sizes: &Vec<u32>, path: &String) -> Result<(ImgFeatures, bool)> {
+
+    let (features, new_entry) = extract_single_deferred(cache, &Semaphore::new(usize::MAX), params, tile_grid_sizes, path)?;
+    let cached = new_entry.is_none();
+
+    /* add to database */
+    if let Some(entry) = new_entry {
+        cache.insert(path, entry)?;
+    }
+
+    Ok((features, cached))
+}
+
+/// like extract_single, but freshly extracted features aren't written to the cache,
+/// their serialized entry is returned (None if they came from the cache) for the caller
+/// to write, e.g. in batches, io_permits is held whenever the file or the cache is accessed
+pub fn extract_single_deferred(cache: &Cache, io_permits: &Semaphore, params: &ExtractParams, tile_grid_sizes: &Vec<u32>, path: &String) -> Result<(ImgFeatures, Option<Vec<u8>>)> {
+
+    let io = io_permits.acquire();
+
+    /* use cached entry if there is one, corrupt entries (or ones from older
+       versions that fail to deserialize) are simply re-extracted, as are
+       entries extracted with other params or missing tiles that were asked for */
+    match cache.get(path) {
+        Ok(Some(ce)) if is_usable(&ce.params, &ce.tile_grid_sizes, params, tile_grid_sizes) => {
+
+            // println!("{}: {}", style("importing from cache").bold().yellow(), path.clone());
+
+            return Ok((ImgFeatures::from_cache(&ce), None))
         },
-        Err(err) => panic!("error with database: {}", err)
+        Ok(_) | Err(Error::CacheCorrupt { .. }) => {},
+        Err(err) => return Err(err)
     }
+
+    /* images that failed before are skipped until the file or the params change */
+    if let Some(failure) = cache.load_failure(path, params)? {
+        return Err(Error::PreviousFailure { failure })
+    }
+
+    let bytes = fs::read(path).map_err(|source| Error::Io { path: path.clone(), source })?;
+    drop(io);
+
+    let features = match extract_uncached(&bytes, params, tile_grid_sizes, path) {
+        Ok(features) => features,
+        Err(err) => {
+            if let Some(failure) = Failure::from_error(path, &err).filter(|f| f.kind.is_persistent()) {
+                let _io = io_permits.acquire();
+                cache.store_failure(&failure, params)?;
+            }
+            return Err(err)
+        }
+    };
+
+    let entry = features.to_cache(path, params, tile_grid_sizes).to_bytes()?;
+
+    // println!("{}: {}", style("added to cache ").bold().green(), path.clone());
+
+    Ok((features, Some(entry)))
+}
+
+/// decodes the image file's bytes and extracts its features, panics in the decoder
+/// or extractor only fail this image
+fn extract_uncached(bytes: &[u8], params: &ExtractParams, tile_grid_sizes: &Vec<u32>, path: &String) -> Result<ImgFeatures> {
+
+    let img = panic::catch_unwind(|| decode_image(path, bytes))
+                    .map_err(|payload| Error::DecodePanic { path: path.clone(), message: panic_message(&payload) })?
+                    .map_err(|err| Error::from_image(path, err))?;
+
+    /* extract keypoints and descriptors */
+    let features = panic::catch_unwind(AssertUnwindSafe(|| extract_from_image(&img, params, tile_grid_sizes)))
+                        .map_err(|payload| Error::Extractor { path: path.clone(), message: panic_message(&payload) })?;
+
+    /* nothing to match against (e.g. blank images) */
+    if features.regions().all(|region| region.keypoints.is_empty()) {
+        return Err(Error::NoKeypoints { path: path.clone() })
+    }
+
+    Ok(features)
+}
+
+/// the feature extractor for params, akaze defaults for anything not set
+fn extractor(params: &ExtractParams) -> Akaze {
+    let default = Akaze::default();
+    Akaze {
+        detector_threshold: params.extractor.detector_threshold.unwrap_or(default.detector_threshold),
+        max_octave_evolution: params.extractor.max_octave_evolution.unwrap_or(default.max_octave_evolution),
+        num_sublevels: params.extractor.num_sublevels.unwrap_or(default.num_sublevels),
+        ..default
+    }
+}
+
+/// resizes img and extracts its keypoints and descriptors (no caching)
+fn extract_region(img: &DynamicImage, params: &ExtractParams) -> ImgFeatures {
+
+    /* make new feature extractor */
+    let akaze = extractor(params);
+
+    let [nwidth, nheight] = params.resize_dims;
+    let filter = FilterType::Nearest;
+    let (width, height) = img.dimensions();
+    let img = img.resize(nwidth, nheight, filter);
+    let (rwidth, rheight) = img.dimensions();
+
+    let (keypoints, descriptors) = akaze.extract(&img);
+
+    ImgFeatures {
+        keypoints,
+        descriptors,
+        original_dims: [width, height],
+        resized_dims: [rwidth, rheight],
+        offset: [0, 0],
+        tiles: Vec::new()
+    }
+}
+
+/// extracts features from the whole (resized) image, plus for each n in tile_grid_sizes
+/// from an n x n grid of overlapping tiles, each resized on its own, so that small
+/// regions of large images keep enough detail to be matched against crops
+pub fn extract_from_image(img: &DynamicImage, params: &ExtractParams, tile_grid_sizes: &Vec<u32>) -> ImgFeatures {
+
+    let mut features = extract_region(img, params);
+
+    let (width, height) = img.dimensions();
+    let [nwidth, nheight] = params.resize_dims;
+
+    for n in tile_grid_sizes.iter().filter(|n| **n >= 2) {
+
+        let (stride_w, stride_h) = (width / n, height / n);
+
+        /* tiles only help if they're bigger than what they get resized down to,
+           and very skinny images (e.g. 4000 x 3) can't be cut into n rows or columns */
+        if stride_w == 0 || stride_h == 0 || (stride_w <= nwidth && stride_h <= nheight) {
+            continue
+        }
+
+        /* tiles overlap their neighbours by half a stride */
+        let (tile_w, tile_h) = ((stride_w * 3 / 2).min(width), (stride_h * 3 / 2).min(height));
+
+        for i in 0..*n {
+            for j in 0..*n {
+                let (x, y) = ((i * stride_w).min(width - tile_w), (j * stride_h).min(height - tile_h));
+                let mut tile = extract_region(&img.crop_imm(x, y, tile_w, tile_h), params);
+                tile.offset = [x, y];
+                features.tiles.push(tile);
+            }
+        }
+    }
+
+    features
+}
+
+/// extracts features for the query image (with the global settings, see Config::settings), plus mirrored
+/// copies of it if enabled in config (akaze descriptors aren't invariant to flips, so mirrored matches
+/// need their own descriptors)
+pub fn extract_query(cache: &Cache, cfg: &Config, path: &String) -> Result<Vec<QueryView>> {
+
+    let (features, _) = extract_single(cache, &cfg.settings().extract, &Vec::new(), path)?;
+    let mut views = vec![QueryView { orientation: Orientation::Original, features, source: 0, roi: None }];
+
+    if !cfg.match_flipped_horizontal && !cfg.match_flipped_vertical {
+        return Ok(views)
+    }
+
+    /* flipped copies aren't cached, they only ever get extracted for the query */
+    let img = open_image(path).map_err(|err| Error::from_image(path, err))?;
+    views.extend(flipped_views(cfg, &img));
+
+    Ok(views)
+}
+
+/// like extract_query, for a query image that's only in memory (e.g. read from stdin), so
+/// nothing gets cached, name is used in error messages (and its extension, if any, as a format hint)
+pub fn extract_query_bytes(cfg: &Config, bytes: &[u8], name: &String) -> Result<Vec<QueryView>> {
+
+    let features = extract_uncached(bytes, &cfg.settings().extract, &Vec::new(), name)?;
+    let mut views = vec![QueryView { orientation: Orientation::Original, features, source: 0, roi: None }];
+
+    if !cfg.match_flipped_horizontal && !cfg.match_flipped_vertical {
+        return Ok(views)
+    }
+
+    let img = decode_image(name, bytes).map_err(|err| Error::from_image(name, err))?;
+    views.extend(flipped_views(cfg, &img));
+
+    Ok(views)
+}
+
+/// combines the views of several query images (e.g. photos of one object from different angles)
+/// into a single query, each view remembers which image it came from
+pub fn merge_queries(queries: Vec<Vec<QueryView>>) -> Vec<QueryView> {
+    queries.into_iter()
+        .enumerate()
+        .flat_map(|(source, views)| views.into_iter().map(move |view| QueryView { source, ..view }))
+        .collect()
+}
+
+/// only searches for the parts of the query image inside regions (original query image
+/// coordinates), a Usage error if none of its keypoints are inside them
+pub fn restrict_query(query: &mut Vec<QueryView>, regions: &[Region]) -> Result<()> {
+
+    for view in query.iter_mut() {
+        view.restrict_to(regions);
+    }
+
+    match query.iter().all(|view| view.features.keypoints.is_empty()) {
+        true => Err(Error::Usage { message: "none of the query image's keypoints are inside the region of interest".to_string() }),
+        false => Ok(())
+    }
+}
+
+/// mirrored copies of the query image enabled in config
+fn flipped_views(cfg: &Config, img: &DynamicImage) -> Vec<QueryView> {
+
+    let mut views = Vec::new();
+    let params = cfg.settings().extract;
+
+    if cfg.match_flipped_horizontal {
+        views.push(QueryView { orientation: Orientation::FlippedHorizontal, features: extract_from_image(&img.fliph(), &params, &Vec::new()), source: 0, roi: None });
+    }
+    if cfg.match_flipped_vertical {
+        views.push(QueryView { orientation: Orientation::FlippedVertical, features: extract_from_image(&img.flipv(), &params, &Vec::new()), source: 0, roi: None });
+    }
+
+    views
 }
 
 pub fn bitarray_to_floatvec(ba: &BitArray<64>) -> Vec<f32> {
@@ -118,6 +502,27 @@ pub fn bitarray_to_floatvec(ba: &BitArray<64>) -> Vec<f32> {
     output
 }
 
+pub fn floatvec_to_bitarray(fv: &Vec<f32>) -> BitArray<64> {
+
+    let mut arr: [u8; 64] = [0 as u8; 64];
+
+    for (i, byte) in fv.iter().enumerate() {
+        arr[i] = byte.clone() as u8;
+    }
+
+    BitArray::new(arr)
+}
+
+pub fn bitarray_to_block(ba: &BitArray<64>) -> Block {
+
+    let mut block: Block = [0; DESCRIPTOR_BYTES];
+    for (i, byte) in ba.iter().enumerate() {
+        block[i] = *byte;
+    }
+
+    block
+}
+
 pub fn floatvec_to_floatarray(fv: &Vec<f32>) -> [f32; 64] {
 
     let mut desc_array: [f32; 64] = [0 as f32; 64];
@@ -129,155 +534,582 @@ pub fn floatvec_to_floatarray(fv: &Vec<f32>) -> [f32; 64] {
     desc_array
 }
 
-fn get_num_matches(ratio_test_ratio: f32, descs_query: &Vec<BitArray<64>>, descs_search: (&Vec<BitArray<64>>, &String)) -> u32 {
+/// finds keypoint matches between query and search descriptors using Lowe's ratio test on
+/// hamming distances between the descriptors' bits (not the squared euclidean distances between
+/// their bytes the kd-tree matcher used, which are distributed differently, so the same
+/// ratio_test_ratio gives different match counts), search descriptors flagged in ignored
+/// (if it isn't empty) are left out, returns (query descriptor index, search descriptor index) pairs
+pub fn get_matches(ratio_test_ratio: f32, descs_query: &[Block], descs_search: &[Block], ignored: &[bool]) -> Vec<(usize, usize)> {
 
-    let (descs, _) = descs_search;
-    
-    /* fit nearest neighbors classifier to query descriptors */
-    let mut kdtree = KdTree::new(64);
+    let mut matches: Vec<(usize, usize)> = Vec::new();
+
+    /* ratio test needs a second nearest neighbor */
+    if descs_search.len() < 2 {
+        return matches
+    }
+
+    let kernel = Kernel::detect();
+    let mut dists: Vec<u32> = vec![0; descs_search.len()];
 
-    for (descnum, desc_ba) in descs.iter().enumerate() {
+    for (qnum, qdesc) in descs_query.iter().enumerate() {
 
-        let desc_vec: Vec<f32> = bitarray_to_floatvec(desc_ba);
-        let desc_array: [f32; 64] = floatvec_to_floatarray(&desc_vec);
-        let _ = kdtree.add(desc_array, descnum);
+        hamming::distances_with(kernel, qdesc, descs_search, &mut dists);
+
+        /* masked out search keypoints can't be anyone's neighbor */
+        for (d, _) in dists.iter_mut().zip(ignored.iter()).filter(|(_, ignore)| **ignore) {
+            *d = u32::MAX;
+        }
+
+        /* nearest and second nearest */
+        let (mut nearest, mut d1, mut d2) = (0, u32::MAX, u32::MAX);
+        for (snum, d) in dists.iter().enumerate() {
+            if *d < d1 {
+                (nearest, d1, d2) = (snum, *d, d1);
+            } else if *d < d2 {
+                d2 = *d;
+            }
+        }
+
+        /* do ratio test, not against a masked out second neighbor */
+        if d2 != u32::MAX && (d1 as f32) < ratio_test_ratio * d2 as f32 {
+            matches.push((qnum, nearest));
+        }
+    }
+
+    matches
+}
+
+/// fits nearest neighbors classifier to (packed) search descriptors
+fn build_kdtree(descs: &[Block]) -> KdTree<f32, usize, [f32; 64]> {
+
+    let mut kdtree = KdTree::new(64);
+
+    for (descnum, desc) in descs.iter().enumerate() {
+        let _ = kdtree.add(desc.map(|byte| byte as f32), descnum);
     };
 
-    let mut num_matches: u32 = 0;
+    kdtree
+}
+
+/// like get_matches, but with a kd-tree over the descriptors' bytes and squared euclidean
+/// distances, the way matching worked before the hamming kernels, kept as a baseline for benches
+pub fn get_matches_kdtree(ratio_test_ratio: f32, descs_query: &[Block], descs_search: &[Block]) -> Vec<(usize, usize)> {
+
+    let kdtree = build_kdtree(descs_search);
+    let mut matches: Vec<(usize, usize)> = Vec::new();
 
-    for qdesc in descs_query.iter() {
+    for (qnum, qdesc) in descs_query.iter().enumerate() {
 
         /* convert query descriptor to float array */
-        let qvec = bitarray_to_floatvec(qdesc);
-        let qarray = floatvec_to_floatarray(&qvec);
+        let qarray = qdesc.map(|byte| byte as f32);
 
-        // let res = index.search(&granne::angular::Vector::from(Vec::from(qvecf32)), 200, 10);
-        let res = kdtree.nearest(&qarray, 10, &squared_euclidean).unwrap();
+        /* only fails on non-finite descriptors, which can't match anything anyway */
+        let res = match kdtree.nearest(&qarray, 10, &squared_euclidean) {
+            Ok(res) => res,
+            Err(_) => continue
+        };
 
         /* do ratio test */
         if res.len() > 1 && res[0].0 / res[1].0 < ratio_test_ratio  {
-            num_matches += 1;
+            matches.push((qnum, *res[0].1));
         }
     }
 
-    num_matches
+    matches
 }
 
-pub fn calculate_similarities(cache: Arc<Mutex<Db>>, cfg: &Config, query_desc: &Vec<BitArray<64>>, search_paths: Vec<String>) -> (Arc<Mutex<Vec<ImgInfo>>>, Vec<String>) {
-    
-    let info: Arc<Mutex<Vec<ImgInfo>>> = Arc::new(Mutex::new(Vec::new()));
+/// a query view along with its descriptors packed for the hamming kernels
+type PackedView<'a> = (&'a QueryView, Vec<Block>);
 
-    let failed_paths_arc: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-    
-    let mut handles = Vec::new();
-    let pb = Arc::new(Mutex::new(tqdm!(total=search_paths.len(), desc="extracting features")));
+/// new cache entries are written this many at a time
+const CACHE_BATCH_SIZE: usize = 64;
 
-    // let m = MultiProgress::new();
-    // let pb = ProgressBar::new(search_paths.len() as u64);
+/// query keypoints have to be matched in at least this many images to be stop-words,
+/// with fewer images searched a fraction of them says little
+const MIN_STOP_WORD_IMAGES: usize = 10;
 
-    /* determine num workers */
-    let num_workers: usize = match cfg.num_workers {
-        0 => num_cpus::get(),
-        _ => cfg.num_workers as usize
+/// result of processing one search image, sent from the workers to the collector
+enum Outcome {
+    /// matched (whether or not there were any matches), with what the score was based on
+    /// (if stop-words are enabled, kept until every image is matched), the new cache entry
+    /// if the features weren't cached yet and the packed descriptors if they weren't packed yet
+    Matched(ImgInfo, Vec<Scored>, Option<Vec<u8>>, Option<PackedFeatures>),
+    Failed(Failure),
+
+    /// something that isn't about this image went wrong, the run stops
+    Fatal(Error)
+}
+
+/// the best matches of a search image with one of the query images
+struct Scored {
+    /// index of the query view that matched
+    view: usize,
+
+    /// the matched region with only its matched keypoints, matches index into those
+    region: ImgFeatures,
+    matches: Vec<(usize, usize)>
+}
+
+/// mask regions (see ScanOptions::mask) and settings of each search directory, most specific directory first
+struct Roots {
+    roots: Vec<(PathBuf, Vec<Region>, SearchSettings)>,
+
+    /// for images that aren't below any of them
+    global: SearchSettings
+}
+
+impl Roots {
+
+    fn new(cfg: &Config) -> Roots {
+
+        let mut roots: Vec<(PathBuf, Vec<Region>, SearchSettings)> = cfg.search_dirs_paths.iter()
+            .filter_map(|root| fs::canonicalize(root.path()).ok().map(|path| (path, root.scan_options(&cfg.scan).mask, root.settings(cfg))))
+            .collect();
+        roots.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.components().count()));
+
+        Roots { roots, global: cfg.settings() }
+    }
+
+    /// mask (empty if it has none) and settings for an image, by canonical path, as discovered
+    fn for_path(&self, path: &str) -> (&[Region], &SearchSettings) {
+        self.roots.iter()
+            .find(|(root, _, _)| Path::new(path).starts_with(root))
+            .map_or((&[][..], &self.global), |(_, mask, settings)| (mask.as_slice(), settings))
+    }
+}
+
+/// gets the search image's features (straight from the descriptor store when they were
+/// packed by an earlier run) and matches the query against them
+fn match_single(cache: &Cache, io_permits: &Semaphore, cfg: &Config, settings: &SearchSettings, query: &[PackedView], mask: &[Region], path: String) -> Outcome {
+
+    /* warm path, nothing is deserialized but the keypoints */
+    let mapped = {
+        let _io = io_permits.acquire();
+        cache.store().get(&path)
     };
-    println!("{} workers", num_workers);
+    match mapped {
+        Ok(Some(mapped)) if is_usable(mapped.params(), mapped.tile_grid_sizes(), &settings.extract, &cfg.tile_grid_sizes) => {
+            let (info, scored) = match_regions(cfg, settings.ratio_test_ratio, query, mask, path, mapped.regions());
+            return Outcome::Matched(info, scored, None, None)
+        },
+        Ok(_) => {},
+        Err(err) => return Outcome::Fatal(err)
+    }
 
-    // let sp = search_paths.to_owned();
-    // let chunks = sp.chunks(sp.len() / num_workers);
-    // let mut chunks_owned = Vec::new();
-    
-    // for chunk in chunks {
-    //     chunks_owned.push(chunk.to_owned());
-    // }
-    let mut chunks_owned: Vec<Vec<String>> = Vec::new();
+    /* get keypoints and descriptors for this search image */
+    let (features, new_entry) = match extract_single_deferred(cache, io_permits, &settings.extract, &cfg.tile_grid_sizes, &path) {
+        Ok(res) => res,
+        Err(err) => return match Failure::from_error(&path, &err) {
+            Some(failure) => Outcome::Failed(failure),
+            None => Outcome::Fatal(err)
+        }
+    };
+
+    /* packed for the next run, also for images cached before the store existed */
+    let packed = features.to_packed(&settings.extract, &cfg.tile_grid_sizes);
+    let (info, scored) = match_regions(cfg, settings.ratio_test_ratio, query, mask, path, packed.regions());
 
-    for (i, sp) in search_paths.iter().enumerate() {
-        
-        let ind = match i >= num_workers {
-            true => i%num_workers,
+    Outcome::Matched(info, scored, new_entry, Some(packed))
+}
+
+/// calculates the similarity of each region (whole image or tile) to the query images (num matches),
+/// keeping whichever orientation of each query image and region matched best, regions are matched
+/// separately so the same feature detected at several scales doesn't defeat the ratio test,
+/// keypoints inside the mask are ignored
+fn match_regions<'a, R>(cfg: &Config, ratio_test_ratio: f32, query: &[PackedView], mask: &[Region], path: String, regions: R) -> (ImgInfo, Vec<Scored>)
+where
+    R: Iterator<Item = (&'a PackedRegion, &'a [Block])>
+{
+
+    /* best (view, region, matches) for each query image */
+    let num_sources = query.iter().map(|(view, _)| view.source + 1).max().unwrap_or(0);
+    let mut best: Vec<Option<(usize, &PackedRegion, Vec<(usize, usize)>)>> = vec![None; num_sources];
+    for (region, descriptors) in regions {
+
+        let ignored: Vec<bool> = match mask.is_empty() {
+            true => Vec::new(),
             false => {
-                chunks_owned.push(Vec::new());
-                i
+                let features = ImgFeatures::from_packed(region);
+                features.keypoints.iter().map(|kp| mask.iter().any(|m| m.contains(features.original_point(kp)))).collect()
             }
         };
 
-        chunks_owned[ind].push(sp.clone())
+        for (v, (view, view_descriptors)) in query.iter().enumerate() {
+            let matches = get_matches(ratio_test_ratio, view_descriptors, descriptors, &ignored);
+            let source_best = &mut best[view.source];
+            if source_best.as_ref().map_or(true, |(_, _, m)| matches.len() > m.len()) {
+                *source_best = Some((v, region, matches));
+            }
+        }
     }
 
-    let ratio_test_ratio = cfg.ratio_test_ratio;
+    let scored: Vec<Scored> = best.into_iter()
+                                .flatten()
+                                .map(|(view, region, matches)| Scored { view, region: ImgFeatures::from_packed(region), matches })
+                                .collect();
+    let info = score_views(cfg.query_score, query, path, &scored);
+
+    /* only the matched keypoints are kept around for stop-word removal */
+    let scored = match cfg.stop_word_fraction > 0.0 {
+        true => scored.into_iter()
+                    .filter(|s| !s.matches.is_empty())
+                    .map(|Scored { view, region, matches }| Scored {
+                        view,
+                        region: ImgFeatures { keypoints: matches.iter().map(|(_, si)| region.keypoints[*si]).collect(), descriptors: Vec::new(), tiles: Vec::new(), ..region },
+                        matches: matches.iter().enumerate().map(|(k, (qi, _))| (*qi, k)).collect()
+                    })
+                    .collect(),
+        false => Vec::new()
+    };
+
+    (info, scored)
+}
+
+/// scores a search image from the best matches with each query image, it's shown (and located)
+/// with whichever query image matched best
+fn score_views(query_score: QueryScore, query: &[PackedView], path: String, scored: &[Scored]) -> ImgInfo {
 
-    /* multithreaded batch feature extraction */
-    for chunk in chunks_owned {
-        // println!("\n\nchunk len: {}", chunk.len());
-        let thisinfo = info.clone();
-        let thispb = pb.clone();
-        let this_qdesc = query_desc.clone();
-        let resize_dims = cfg.resize_dimensions;
-        let thiscache = cache.clone();
-        let thisfailedpaths = failed_paths_arc.clone();
-        let print_results = cfg.print_live_analysis_results;
+    /* first of the best on ties */
+    let primary = match scored.iter().rev().max_by_key(|s| s.matches.len()) {
+        Some(primary) => primary,
+        None => return ImgInfo { path, num_matches: 0, location: None, orientation: Orientation::Original, source: 0 } /* empty query */
+    };
+    let view = query[primary.view].0;
+
+    let num_matches = match query_score {
+        QueryScore::Best => primary.matches.len(),
+        QueryScore::Sum => scored.iter().map(|s| s.matches.len()).sum()
+    };
 
-        // let pb = m.add(ProgressBar::new(0));
+    /* estimate where the query sits in this image */
+    let location = localize(&view.features, view.roi, &primary.region, &primary.matches);
 
-        // pool.execute(move || {
-        handles.push(thread::spawn(move || {
+    ImgInfo { path, num_matches: num_matches as u32, location, orientation: view.orientation, source: view.source }
+}
 
-            // set_current_thread_priority(ThreadPriority::Max).unwrap();
+/// finds query keypoints matched in at least stop_word_fraction of the images (e.g. a watermark
+/// shared by the query and the whole corpus) and takes their matches out of every image's score,
+/// the ratio test is done for each query keypoint on its own, so this is the same as leaving them
+/// out of matching, returns the number of stop-words
+fn drop_stop_words(info: &mut Vec<ImgInfo>, mut scored: Vec<Vec<Scored>>, query: &[PackedView], cfg: &Config) -> usize {
 
-            for path in chunk.to_owned() {   
-                
-                let mut _msg: String = String::new();
-                
-                /* get keypoints and descriptors for this search image */
-                match extract_single(thiscache.clone(), resize_dims, &path) {
+    /* in how many images each (view, query keypoint) matched */
+    let mut counts: HashMap<(usize, usize), usize> = HashMap::new();
+    for s in scored.iter().flatten() {
+        for (qi, _) in s.matches.iter() {
+            *counts.entry((s.view, *qi)).or_default() += 1;
+        }
+    }
 
-                    Some((_, descriptors, cached)) => {
+    let min_images = ((cfg.stop_word_fraction * info.len() as f32).ceil() as usize).max(MIN_STOP_WORD_IMAGES);
+    let stop_words: HashSet<(usize, usize)> = counts.into_iter().filter(|(_, n)| *n >= min_images).map(|(key, _)| key).collect();
+    if stop_words.is_empty() {
+        return 0
+    }
 
-                        /* calculte similarity to query image (num matches) */
-                        let num_matches = get_num_matches(ratio_test_ratio, &this_qdesc, (&descriptors, &path));
-                        if print_results {
+    for (img_info, img_scored) in info.iter_mut().zip(scored.iter_mut()) {
 
-                            let path_styled = style(path.clone()).bold();
-                            let (path_styled, cached_flag) = match cached {
-                                true => (path_styled.blue(), style("(cached)").bold().blue()),
-                                false => (path_styled.cyan(), style("").bold().dim())
-                            };
-                            _msg = format!("{:>6} matches <- {} {}", num_matches, path_styled, cached_flag);
+        let mut changed = false;
+        for s in img_scored.iter_mut() {
+            let view = s.view;
+            let before = s.matches.len();
+            s.matches.retain(|(qi, _)| !stop_words.contains(&(view, *qi)));
+            changed |= s.matches.len() < before;
+        }
+
+        if changed {
+            *img_info = score_views(cfg.query_score, query, img_info.path.clone(), img_scored);
+        }
+    }
+
+    stop_words.len()
+}
+
+/// matches the query against every search image, search_paths can be a plain list or a
+/// stream still being filled (e.g. a DiscoveryStream), workers pick up paths as they arrive,
+/// images that can't be processed are returned as failures (sorted by path),
+/// errors that aren't about a single image (e.g. a broken cache) stop the whole run
+pub fn calculate_similarities<I>(cache: &Cache, cfg: &Config, query: &Vec<QueryView>, search_paths: I) -> Result<(Vec<ImgInfo>, Vec<Failure>)>
+where
+    I: IntoIterator<Item = String>,
+    I::IntoIter: Send
+{
+
+    /* total is unknown (0) while paths are still being discovered */
+    let search_paths = search_paths.into_iter();
+    let total = search_paths.size_hint().1.unwrap_or(0);
+    let mut pb = tqdm!(total=total, desc="extracting features");
+
+    /* decoding, extraction and matching run on a work stealing pool so one slow image
+       doesn't hold up others, file and cache access has its own (optional) limit */
+    let (pool, io_permits) = worker_pool(cfg)?;
+    let num_workers = pool.current_num_threads();
+    println!("{} workers", num_workers);
+
+    /* bounded, so workers can't run far ahead of the collector */
+    let (sender, receiver) = sync_channel::<Outcome>(num_workers * 2);
+    let print_results = cfg.print_live_analysis_results;
+
+    /* set when the run can't go on, workers stop taking new paths */
+    let stop = AtomicBool::new(false);
+
+    /* query descriptors are packed once, not for every search image */
+    let query: Vec<PackedView> = query.iter()
+                                    .map(|view| (view, view.features.descriptors.iter().map(bitarray_to_block).collect()))
+                                    .collect();
+    let roots = Roots::new(cfg);
+
+    let (mut info, scored, mut failures, fatal) = thread::scope(|scope| {
+
+        let (io_permits, stop, query, roots) = (&io_permits, &stop, &query, &roots);
+
+        /* collect results as they come in, new cache entries are written in batches */
+        let collector = scope.spawn(move || {
+
+            let mut info: Vec<ImgInfo> = Vec::new();
+            let mut scored: Vec<Vec<Scored>> = Vec::new();
+            let mut failures: Vec<Failure> = Vec::new();
+            let mut fatal: Option<Error> = None;
+            let mut batch = CacheBatch::default();
+
+            for outcome in receiver {
+
+                let msg = match outcome {
+                    Outcome::Matched(img_info, img_scored, new_entry, packed) => {
+                        let cached = new_entry.is_none();
+                        if let Some(entry) = new_entry {
+                            batch.insert(img_info.path.clone(), entry);
+                        }
+                        if let Some(packed) = packed {
+                            batch.insert_packed(img_info.path.clone(), packed);
                         }
-            
-                        /* add extracted info to output */
-                        let mut thisinfo_guard = thisinfo.lock().unwrap();
-                        thisinfo_guard.push(ImgInfo { path, num_matches });
-                        drop(thisinfo_guard);
+
+                        let path_styled = style(img_info.path.clone()).bold();
+                        let (path_styled, cached_flag) = match cached {
+                            true => (path_styled.blue(), style("(cached)").bold().blue()),
+                            false => (path_styled.cyan(), style("").bold().dim())
+                        };
+                        let msg = format!("{:>6} matches <- {} {}", img_info.num_matches, path_styled, cached_flag);
+                        info.push(img_info);
+                        scored.push(img_scored);
+                        msg
+                    },
+                    Outcome::Failed(failure) => {
+                        let msg = format!("{}: {}, skipping", style("ERROR").bold().bright().red(), failure);
+                        failures.push(failure);
+                        msg
                     },
+                    Outcome::Fatal(err) => {
+                        fatal.get_or_insert(err);
+                        stop.store(true, Ordering::Relaxed);
+                        continue
+                    }
+                };
 
-                    None => {
-                        if print_results {
-                            _msg = format!("{}: unable to open {}, skipping", style("ERROR").bold().bright().red(), style(path.clone()).bold());
-                        }
-                        let mut failed_paths = thisfailedpaths.lock().unwrap();
-                        failed_paths.push(path.clone());
+                if batch.len() >= CACHE_BATCH_SIZE {
+                    let _io = io_permits.acquire();
+                    if let Err(err) = cache.write_batch(std::mem::take(&mut batch)) {
+                        fatal.get_or_insert(err);
+                        stop.store(true, Ordering::Relaxed);
                     }
                 }
 
-                let mut p = thispb.lock().unwrap();
-                p.update(1);
+                pb.update(1);
                 if print_results {
-                    p.write(_msg);
+                    pb.write(msg);
+                }
+            }
+
+            /* whatever's left over */
+            if !batch.is_empty() && fatal.is_none() {
+                if let Err(err) = cache.write_batch(batch) {
+                    fatal.get_or_insert(err);
+                }
+            }
+
+            (info, scored, failures, fatal)
+        });
+
+        /* multithreaded batch feature extraction, stops taking new paths after a fatal error */
+        let _ = pool.install(|| {
+            search_paths.par_bridge().try_for_each_with(sender, |sender, path| {
+
+                if stop.load(Ordering::Relaxed) {
+                    return Err(())
+                }
+
+                /* a panic only fails the image it happened on */
+                let (mask, settings) = roots.for_path(&path);
+                let outcome = match panic::catch_unwind(AssertUnwindSafe(|| match_single(cache, io_permits, cfg, settings, query, mask, path.clone()))) {
+                    Ok(outcome) => outcome,
+                    Err(payload) => Outcome::Failed(Failure { path, kind: FailureKind::MatchingPanic, message: panic_message(&payload) })
+                };
+
+                let stop = matches!(outcome, Outcome::Fatal(_));
+                match sender.send(outcome) {
+                    Ok(_) if !stop => Ok(()),
+                    _ => Err(())
+                }
+            })
+        });
+
+        /* a panic in the collector would lose every result, so the run fails rather than reporting none */
+        collector.join().map_err(|payload| Error::Internal { message: format!("collecting results panicked: {}", panic_message(&payload)) })
+    })?;
+    eprint!("\n");
+
+    if let Some(err) = fatal {
+        return Err(err)
+    }
+
+    /* sled flushes in the background too, but make sure this run's entries are on disk
+       (and their packed descriptors readable by the next run) */
+    cache.flush()?;
+
+    if cfg.stop_word_fraction > 0.0 {
+        let num_stop_words = drop_stop_words(&mut info, scored, &query, cfg);
+        if num_stop_words > 0 {
+            println!("ignoring {} query keypoints matched in too many images", num_stop_words);
+        }
+    }
+
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok((info, failures))
+}
+
+/// extracts, caches and packs the features of every search image that doesn't have usable ones
+/// cached yet, without matching anything (e.g. so timing searches isn't skewed by first-time
+/// extraction), returns the images that couldn't be processed (sorted by path)
+pub fn extract_and_cache(cache: &Cache, cfg: &Config, search_paths: &[String]) -> Result<Vec<Failure>> {
+
+    let (pool, io_permits) = worker_pool(cfg)?;
+    let roots = Roots::new(cfg);
+    let mut pb = tqdm!(total=search_paths.len(), desc="extracting features");
+    let mut failures: Vec<Failure> = Vec::new();
+
+    /* new entries are written after each chunk, like the batches of a search */
+    for chunk in search_paths.chunks(CACHE_BATCH_SIZE) {
+
+        let outcomes: Vec<(&String, Result<Option<(Option<Vec<u8>>, PackedFeatures)>>)> = pool.install(|| {
+            chunk.par_iter().map(|path| (path, extract_unpacked(cache, &io_permits, cfg, roots.for_path(path).1, path))).collect()
+        });
+
+        let mut batch = CacheBatch::default();
+        for (path, outcome) in outcomes {
+            match outcome {
+                Ok(Some((new_entry, packed))) => {
+                    if let Some(entry) = new_entry {
+                        batch.insert(path.clone(), entry);
+                    }
+                    batch.insert_packed(path.clone(), packed);
+                },
+                Ok(None) => {},
+                Err(err) => match Failure::from_error(path, &err) {
+                    Some(failure) => failures.push(failure),
+                    None => return Err(err)
                 }
-                drop(p);
             }
-        }));
+        }
+
+        cache.write_batch(batch)?;
+        pb.update(chunk.len());
     }
     eprint!("\n");
 
-    /* make sure all threads are finished before returning */
-    for handle in handles {
-        handle.join().unwrap();
+    cache.flush()?;
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(failures)
+}
+
+/// the features of a search image (and its new cache entry, if it wasn't cached) packed for the
+/// descriptor store, None if they're already packed with the right settings
+fn extract_unpacked(cache: &Cache, io_permits: &Semaphore, cfg: &Config, settings: &SearchSettings, path: &String) -> Result<Option<(Option<Vec<u8>>, PackedFeatures)>> {
+
+    let mapped = {
+        let _io = io_permits.acquire();
+        cache.store().get(path)?
+    };
+    if mapped.map_or(false, |mapped| is_usable(mapped.params(), mapped.tile_grid_sizes(), &settings.extract, &cfg.tile_grid_sizes)) {
+        return Ok(None)
+    }
+
+    let (features, new_entry) = extract_single_deferred(cache, io_permits, &settings.extract, &cfg.tile_grid_sizes, path)?;
+    Ok(Some((new_entry, features.to_packed(&settings.extract, &cfg.tile_grid_sizes))))
+}
+
+/// work stealing pool of num_workers threads (one per cpu if 0) and the permits
+/// limiting file and cache access to num_io_workers at once (unlimited if 0)
+fn worker_pool(cfg: &Config) -> Result<(ThreadPool, Semaphore)> {
+
+    let num_workers: usize = match cfg.num_workers {
+        0 => num_cpus::get(),
+        _ => cfg.num_workers as usize
+    };
+    let pool = ThreadPoolBuilder::new()
+                    .num_threads(num_workers)
+                    .build()
+                    .map_err(|err| Error::Config { message: format!("unable to start {} workers: {}", num_workers, err) })?;
+    let io_permits = Semaphore::new(match cfg.num_io_workers {
+        0 => usize::MAX,
+        n => n as usize
+    });
+
+    Ok((pool, io_permits))
+}
+
+/// picks out images with an "outlier" number of matches (z-score above thresh),
+/// returns the mean and std dev of the match counts along with the outliers and their z-scores
+pub fn find_outliers(info: &Vec<ImgInfo>, zscore_thresh: f32) -> (f32, f32, Vec<(f32, &ImgInfo)>) {
+
+    /* calculate mean and std dev of distances */
+    let nmatches_list: Vec<f32> = info.iter().map(|x| x.num_matches as f32).collect();
+    if nmatches_list.len() < 2 {
+        return (0.0, 0.0, Vec::new())
     }
-    // m.clear().unwrap();
+    let mean = mean(&nmatches_list);
+    let stddev = standard_deviation(&nmatches_list, Some(mean));
 
-    let failed_paths = failed_paths_arc.lock().unwrap().iter().map(|x| x.clone()).collect();
+    /* filter matches from list */
+    let mut matches: Vec<(f32, &ImgInfo)> = Vec::new();
+    for entry in info.iter() {
+        let z: f32 = (entry.num_matches as f32 - mean) / stddev;
+            if z > zscore_thresh {
+                matches.push((z, entry));
+            }
+    }
+
+    (mean, stddev, matches)
+}
+
+/// the matches (see find_outliers) to report with the result options in config: matches with
+/// fewer than min_matches or not matching any of the path globs are left out, the rest are
+/// sorted and cut off after top_k, returns mean, std dev and the matches like find_outliers
+pub fn select_results<'a>(info: &'a Vec<ImgInfo>, cfg: &Config) -> Result<(f32, f32, Vec<(f32, &'a ImgInfo)>)> {
+
+    let opts = &cfg.results;
+    let (mean, stddev, mut matches) = find_outliers(info, cfg.outlier_zscore_thresh);
+
+    /* globs are matched against the full path or any directory above it */
+    let paths = build_glob_matcher("/", &opts.paths)?;
+    matches.retain(|(_, m)| {
+        m.num_matches >= opts.min_matches.unwrap_or(0)
+            && (opts.paths.is_empty() || Path::new(&m.path).ancestors().enumerate().any(|(i, p)| paths.matched(p, i > 0).is_ignore()))
+    });
+
+    /* ties (and files whose mtime or size can't be read, which go last) are ordered by path */
+    let metadata = |m: &ImgInfo| fs::metadata(&m.path).ok();
+    match opts.sort_by {
+        SortBy::Score => matches.sort_by(|(za, a), (zb, b)| zb.total_cmp(za).then_with(|| a.path.cmp(&b.path))),
+        SortBy::Path => matches.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path)),
+        SortBy::Mtime => matches.sort_by_cached_key(|(_, m)| (std::cmp::Reverse(metadata(m).and_then(|meta| meta.modified().ok())), m.path.clone())),
+        SortBy::Size => matches.sort_by_cached_key(|(_, m)| (std::cmp::Reverse(metadata(m).map(|meta| meta.len())), m.path.clone()))
+    }
+
+    if let Some(top_k) = opts.top_k {
+        matches.truncate(top_k);
+    }
 
-    (info, failed_paths)
+    Ok((mean, stddev, matches))
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+/* hamming distance between 512 bit (packed akaze) descriptors, with hand written kernels
+   picked at runtime: AVX-512 VPOPCNTDQ (needs the avx512 cargo feature), AVX2 or plain popcount */
+
+use crate::store::{Block, DESCRIPTOR_BYTES};
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// a hamming distance implementation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kernel {
+    /// one 512 bit xor and popcount per descriptor
+    Avx512,
+    /// nibble lookup popcount on two 256 bit halves
+    Avx2,
+    /// 64 bit popcounts
+    Scalar
+}
+
+impl Kernel {
+
+    /// the fastest kernel this cpu (and build) supports
+    pub fn detect() -> Kernel {
+        [Kernel::Avx512, Kernel::Avx2].into_iter().find(|k| k.is_supported()).unwrap_or(Kernel::Scalar)
+    }
+
+    pub fn is_supported(&self) -> bool {
+        match self {
+            #[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+            Kernel::Avx512 => is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512vpopcntdq"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2"),
+            Kernel::Scalar => true,
+            #[allow(unreachable_patterns)]
+            _ => false
+        }
+    }
+}
+
+/// hamming distance between two descriptors
+pub fn distance(a: &Block, b: &Block) -> u32 {
+    scalar::distance(a, b)
+}
+
+/// hamming distances from query to each of targets, out[i] is the distance to targets[i]
+pub fn distances(query: &Block, targets: &[Block], out: &mut [u32]) {
+    distances_with(Kernel::detect(), query, targets, out)
+}
+
+/// like distances, with the given kernel, panics if this cpu doesn't support it
+pub fn distances_with(kernel: Kernel, query: &Block, targets: &[Block], out: &mut [u32]) {
+
+    assert_eq!(targets.len(), out.len(), "one output per target");
+    assert!(kernel.is_supported(), "{:?} kernel isn't supported on this cpu", kernel);
+
+    match kernel {
+        /* SAFETY: the cpu features the kernels are compiled for were checked above */
+        #[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+        Kernel::Avx512 => unsafe { avx512::distances(query, targets, out) },
+        #[cfg(target_arch = "x86_64")]
+        Kernel::Avx2 => unsafe { avx2::distances(query, targets, out) },
+        _ => scalar::distances(query, targets, out)
+    }
+}
+
+mod scalar {
+
+    use super::*;
+
+    const WORD_BYTES: usize = 8;
+
+    pub fn distance(a: &Block, b: &Block) -> u32 {
+
+        let mut dist = 0;
+        for i in (0..DESCRIPTOR_BYTES).step_by(WORD_BYTES) {
+            let x = u64::from_ne_bytes(a[i..i + WORD_BYTES].try_into().unwrap());
+            let y = u64::from_ne_bytes(b[i..i + WORD_BYTES].try_into().unwrap());
+            dist += (x ^ y).count_ones();
+        }
+
+        dist
+    }
+
+    pub fn distances(query: &Block, targets: &[Block], out: &mut [u32]) {
+        for (target, dist) in targets.iter().zip(out.iter_mut()) {
+            *dist = distance(query, target);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+
+    use super::*;
+
+    /// popcount of each byte, looked up one nibble at a time
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn popcount_bytes(v: __m256i) -> __m256i {
+
+        let lookup = _mm256_setr_epi8(
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+            0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4
+        );
+        let low_mask = _mm256_set1_epi8(0x0f);
+
+        let lo = _mm256_and_si256(v, low_mask);
+        let hi = _mm256_and_si256(_mm256_srli_epi16(v, 4), low_mask);
+        _mm256_add_epi8(_mm256_shuffle_epi8(lookup, lo), _mm256_shuffle_epi8(lookup, hi))
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn distances(query: &Block, targets: &[Block], out: &mut [u32]) {
+
+        let q0 = _mm256_loadu_si256(query.as_ptr() as *const __m256i);
+        let q1 = _mm256_loadu_si256(query.as_ptr().add(32) as *const __m256i);
+
+        for (target, dist) in targets.iter().zip(out.iter_mut()) {
+
+            let t0 = _mm256_loadu_si256(target.as_ptr() as *const __m256i);
+            let t1 = _mm256_loadu_si256(target.as_ptr().add(32) as *const __m256i);
+
+            /* per byte counts of both halves fit in a byte (at most 16), then sum them into four u64s */
+            let counts = _mm256_add_epi8(popcount_bytes(_mm256_xor_si256(q0, t0)), popcount_bytes(_mm256_xor_si256(q1, t1)));
+            let sums = _mm256_sad_epu8(counts, _mm256_setzero_si256());
+
+            let sums = _mm_add_epi64(_mm256_castsi256_si128(sums), _mm256_extracti128_si256(sums, 1));
+            *dist = (_mm_cvtsi128_si64(sums) + _mm_extract_epi64(sums, 1)) as u32;
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "avx512"))]
+mod avx512 {
+
+    use super::*;
+
+    #[target_feature(enable = "avx512f,avx512vpopcntdq")]
+    pub unsafe fn distances(query: &Block, targets: &[Block], out: &mut [u32]) {
+
+        let q = _mm512_loadu_si512(query.as_ptr() as *const i32);
+
+        for (target, dist) in targets.iter().zip(out.iter_mut()) {
+            let x = _mm512_xor_si512(q, _mm512_loadu_si512(target.as_ptr() as *const i32));
+            *dist = _mm512_reduce_add_epi64(_mm512_popcnt_epi64(x)) as u32;
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use toml::value::{Table, Value};
+
+/// environment variables starting with this override config values, LRIS_SCAN__MAX_DEPTH sets scan.max_depth
+pub const ENV_PREFIX: &str = "LRIS_";
+
+/// environment variable selecting a profile when --profile isn't given
+pub const PROFILE_ENV: &str = "LRIS_PROFILE";
+
+/// table holding the named profiles in config files, [profile.fast]
+const PROFILES_KEY: &str = "profile";
+
+/// project config file used when none is given, it's fine for it not to exist
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// where the config comes from, lowest to highest precedence: defaults, the user config file,
+/// the project config file, the selected profile, LRIS_* environment variables and --set overrides
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources {
+    /// user level config file, None looks in the usual place (see user_config_path)
+    pub user_file: Option<PathBuf>,
+
+    /// project level config file, None uses config.toml if there is one
+    pub project_file: Option<String>,
+
+    /// profile to apply, None uses LRIS_PROFILE if it's set
+    pub profile: Option<String>,
+
+    /// environment variables (only LRIS_* ones are used)
+    pub env: Vec<(String, String)>,
+
+    /// key=value overrides, keys are dotted paths like scan.max_depth
+    pub overrides: Vec<String>
+}
+
+/// where a config value came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+
+    /// a [profile.<name>] table in a config file
+    Profile { name: String, file: String },
+
+    /// an LRIS_* environment variable, by name
+    Env(String),
+
+    /// a --set key=value override, as given
+    Set(String)
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(file) => write!(f, "{}", file),
+            Source::Profile { name, file } => write!(f, "profile {} ({})", name, file),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::Set(overriding) => write!(f, "--set {}", overriding)
+        }
+    }
+}
+
+/// the effective config along with where each of its values came from
+#[derive(Debug, Clone)]
+pub struct LayeredConfig {
+    pub config: Config,
+
+    /// the merged values the config was read from
+    pub value: Table,
+
+    /// source of each value that isn't a default by dotted key, e.g. "scan.max_depth" -> Env("LRIS_SCAN__MAX_DEPTH")
+    pub sources: BTreeMap<String, Source>,
+
+    /// config files that were read, lowest precedence first
+    pub files: Vec<String>
+}
+
+impl LayeredConfig {
+
+    /// where the value at a dotted key came from
+    pub fn source(&self, key: &str) -> Source {
+        self.sources.get(key).cloned().unwrap_or(Source::Default)
+    }
+
+    /// every value as a dotted key = value line, followed by its source
+    pub fn show(&self) -> String {
+        let mut lines: Vec<(String, String)> = Vec::new();
+        flatten(&self.value, "", &mut lines);
+
+        let width = lines.iter().map(|(key, value)| key.len() + value.len() + 3).max().unwrap_or(0);
+        lines.iter()
+            .map(|(key, value)| {
+                let line = format!("{} = {}", key, value);
+                let source = self.source(key);
+                format!("{:<width$}  # {}\n", line, source, width = width)
+            })
+            .collect()
+    }
+}
+
+/// key = value pairs of every leaf (anything that isn't a table) under prefix
+pub(crate) fn flatten(table: &Table, prefix: &str, out: &mut Vec<(String, String)>) {
+    for (key, value) in table.iter() {
+        let key = join_key(prefix, key);
+        match value {
+            Value::Table(t) => flatten(t, &key, out),
+            v => out.push((key, inline(v)))
+        }
+    }
+}
+
+/// a value on a single line, tables inline
+fn inline(value: &Value) -> String {
+    match value {
+        Value::Array(a) => format!("[{}]", a.iter().map(inline).collect::<Vec<String>>().join(", ")),
+        Value::Table(t) => format!("{{ {} }}", t.iter().map(|(k, v)| format!("{} = {}", k, inline(v))).collect::<Vec<String>>().join(", ")),
+        v => v.to_string()
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    match prefix.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", prefix, key)
+    }
+}
+
+/// forgets the sources of everything below key (it's been replaced as a whole)
+fn forget_below(sources: &mut BTreeMap<String, Source>, key: &str) {
+    let below = format!("{}.", key);
+    sources.retain(|k, _| !k.starts_with(&below));
+}
+
+/// merges src into dst, tables are merged key by key while anything else replaces what was there,
+/// each replaced value's source is set by source_of (given the dotted key)
+fn merge(dst: &mut Table, src: &Table, prefix: &str, sources: &mut BTreeMap<String, Source>, source_of: &dyn Fn(&str) -> Source) {
+    for (key, value) in src.iter() {
+        let full_key = join_key(prefix, key);
+        match (dst.get_mut(key), value) {
+            (Some(Value::Table(d)), Value::Table(s)) => merge(d, s, &full_key, sources, source_of),
+            _ => {
+                forget_below(sources, &full_key);
+                dst.insert(key.clone(), value.clone());
+                mark(value, &full_key, sources, source_of);
+            }
+        }
+    }
+}
+
+/// sets the source of a value and of everything in it, if it's a table
+fn mark(value: &Value, key: &str, sources: &mut BTreeMap<String, Source>, source_of: &dyn Fn(&str) -> Source) {
+    match value {
+        Value::Table(t) => for (k, v) in t.iter() {
+            mark(v, &join_key(key, k), sources, source_of);
+        },
+        _ => {
+            sources.insert(key.to_string(), source_of(key));
+        }
+    }
+}
+
+/// sets the value at a dotted key, creating (or replacing non-table values with) tables on the way
+pub(crate) fn set(root: &mut Table, key: &str, value: Value, source: Source, sources: &mut BTreeMap<String, Source>) {
+
+    let parts: Vec<&str> = key.split('.').collect();
+    let mut table = root;
+    for (i, part) in parts[..parts.len() - 1].iter().enumerate() {
+        let entry = table.entry(part.to_string()).or_insert_with(|| Value::Table(Table::new()));
+        if !entry.is_table() {
+            *entry = Value::Table(Table::new());
+            sources.remove(&parts[..=i].join("."));
+        }
+        table = entry.as_table_mut().unwrap();
+    }
+
+    let mut src = Table::new();
+    src.insert(parts[parts.len() - 1].to_string(), value);
+    let prefix = parts[..parts.len() - 1].join(".");
+    merge(table, &src, &prefix, sources, &|_| source.clone());
+}
+
+/// a value as written on the command line or in an environment variable: anything toml
+/// (numbers, booleans, arrays, quoted strings), or else the text as a string
+pub(crate) fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut t| t.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// reads a config file into a table
+fn read_table(filepath: &str) -> Result<Table> {
+    let data = fs::read_to_string(filepath)
+                    .map_err(|err| Error::Config { message: format!("unable to read config file {}: {}", filepath, err) })?;
+    toml::from_str(&data).map_err(|err| Error::Config { message: format!("unable to parse config file {}: {}", filepath, err) })
+}
+
+/// where the user level config file is looked for: $XDG_CONFIG_HOME or ~/.config (%APPDATA% on windows),
+/// in local-reverse-image-search/config.toml
+pub fn user_config_path(env: &[(String, String)]) -> Option<PathBuf> {
+    let var = |name: &str| env.iter().find(|(k, _)| k == name).map(|(_, v)| PathBuf::from(v)).filter(|v| !v.as_os_str().is_empty());
+    let base = var("XDG_CONFIG_HOME")
+                .or_else(|| var("HOME").map(|home| home.join(".config")))
+                .or_else(|| var("APPDATA"))?;
+    Some(base.join("local-reverse-image-search").join("config.toml"))
+}
+
+/// builds the effective config from every source, see ConfigSources
+pub fn load_layered(sources: &ConfigSources) -> Result<LayeredConfig> {
+
+    let mut value = match Value::try_from(Config::default()) {
+        Ok(Value::Table(t)) => t,
+        _ => return Err(Error::Config { message: "unable to build the default config".to_string() })
+    };
+    let mut origin: BTreeMap<String, Source> = BTreeMap::new();
+    let mut files: Vec<String> = Vec::new();
+
+    /* user level then project level file, a missing file is only an error if it was asked for */
+    let config_files = [
+        (sources.user_file.clone().or_else(|| user_config_path(&sources.env)), sources.user_file.is_none()),
+        (Some(PathBuf::from(sources.project_file.as_deref().unwrap_or(DEFAULT_CONFIG_FILE))), sources.project_file.is_none())
+    ];
+    for (file, optional) in config_files.into_iter() {
+        let file = match file {
+            Some(file) if !optional || file.exists() => file.to_string_lossy().to_string(),
+            _ => continue
+        };
+        merge(&mut value, &read_table(&file)?, "", &mut origin, &|_| Source::File(file.clone()));
+        files.push(file);
+    }
+
+    /* profiles come from any of the files, but only the selected one is applied */
+    let profiles = match value.remove(PROFILES_KEY) {
+        Some(Value::Table(t)) => t,
+        Some(_) => return Err(Error::Config { message: format!("{} has to be a table of profiles, e.g. [{}.fast]", PROFILES_KEY, PROFILES_KEY) }),
+        None => Table::new()
+    };
+    let profile = sources.profile.clone()
+                    .or_else(|| sources.env.iter().find(|(k, _)| k == PROFILE_ENV).map(|(_, v)| v.clone()))
+                    .filter(|p| !p.is_empty());
+    if let Some(name) = profile {
+        let table = match profiles.get(&name) {
+            Some(Value::Table(t)) => t,
+            _ => {
+                let names: Vec<&String> = profiles.keys().collect();
+                return Err(Error::Config { message: format!("no profile named {} (profiles: {:?})", name, names) })
+            }
+        };
+        let profile_origin = origin.clone();
+        let prefix = format!("{}.{}", PROFILES_KEY, name);
+        merge(&mut value, table, "", &mut origin, &|key| {
+            let file = match profile_origin.get(&join_key(&prefix, key)) {
+                Some(Source::File(file)) => file.clone(),
+                _ => String::new()
+            };
+            Source::Profile { name: name.clone(), file }
+        });
+    }
+    origin.retain(|k, _| !k.starts_with(&format!("{}.", PROFILES_KEY)));
+
+    /* environment, sorted so the result doesn't depend on the order variables are listed in */
+    let mut env: Vec<&(String, String)> = sources.env.iter().filter(|(k, _)| k.starts_with(ENV_PREFIX) && k != PROFILE_ENV).collect();
+    env.sort();
+    for (name, raw) in env {
+        let key = name[ENV_PREFIX.len()..].to_lowercase().replace("__", ".");
+        set(&mut value, &key, parse_value(raw), Source::Env(name.clone()), &mut origin);
+    }
+
+    /* command line */
+    for overriding in sources.overrides.iter() {
+        let (key, raw) = match overriding.split_once('=') {
+            Some((key, raw)) if !key.trim().is_empty() => (key.trim(), raw.trim()),
+            _ => return Err(Error::Usage { message: format!("--set {} isn't key=value", overriding) })
+        };
+        set(&mut value, key, parse_value(raw), Source::Set(overriding.clone()), &mut origin);
+    }
+
+    let config: Config = Value::Table(value.clone()).try_into()
+                            .map_err(|err| Error::Config { message: format!("invalid config (from {}): {}", files.join(", "), err) })?;
+
+    Ok(LayeredConfig { config, value, sources: origin, files })
+}
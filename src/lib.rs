@@ -0,0 +1,17 @@
+/* library target, lets integration tests (and anything else) drive the search pipeline */
+#![cfg_attr(feature = "avx512", feature(stdsimd, avx512_target_feature))]
+
+pub mod actions;
+pub mod augment;
+pub mod cache;
+pub mod collections;
+pub mod config;
+pub mod error;
+pub mod feature_matching;
+pub mod hamming;
+pub mod layers;
+pub mod localization;
+pub mod region;
+pub mod store;
+pub mod utils;
+pub mod validation;
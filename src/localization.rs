@@ -0,0 +1,270 @@
+use crate::feature_matching::ImgFeatures;
+use crate::utils::XorShift;
+
+use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// minimum number of keypoint matches needed before trying to localize
+pub const MIN_MATCHES_FOR_LOCALIZATION: usize = 8;
+
+/// minimum number of RANSAC inliers needed to accept a homography
+const MIN_INLIERS: usize = 6;
+
+/// number of RANSAC iterations
+const RANSAC_ITERATIONS: usize = 500;
+
+/// max reprojection error (in resized image pixels) for a match to count as an inlier
+const RANSAC_INLIER_THRESH: f64 = 3.0;
+
+/// where the query image (or its region of interest) sits inside a matched search image,
+/// all coordinates are in the full size search image's original pixel coordinates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Location {
+    /// query image (or region of interest) corners projected into the search image
+    /// (top left, top right, bottom right, bottom left)
+    pub corners: [(f32, f32); 4],
+
+    /// axis aligned bounding box of the corners --> [x, y, width, height]
+    pub bbox: [f32; 4],
+
+    /// number of keypoint matches agreeing with the estimated transform
+    pub num_inliers: u32
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [x, y, w, h] = self.bbox;
+        write!(f, "x: {:.0}, y: {:.0}, w: {:.0}, h: {:.0}", x, y, w, h)
+    }
+}
+
+/// 3x3 homography, row major, with the last element fixed to 1
+type Homography = [f64; 9];
+
+/// estimates where the query image is inside the search image using the
+/// keypoint correspondences (query index, search index) found during matching,
+/// roi ([x, y, width, height] in the query's original pixels) outlines just that
+/// part of the query, returns None if no consistent transform could be found
+pub fn localize(query: &ImgFeatures, roi: Option<[f32; 4]>, search: &ImgFeatures, matches: &Vec<(usize, usize)>) -> Option<Location> {
+
+    if matches.len() < MIN_MATCHES_FOR_LOCALIZATION {
+        return None
+    }
+
+    /* gather matched point pairs (in resized image coordinates) */
+    let pairs: Vec<((f64, f64), (f64, f64))> = matches.iter().map(|(qi, si)| {
+        let (qx, qy) = query.keypoints[*qi].point;
+        let (sx, sy) = search.keypoints[*si].point;
+        ((qx as f64, qy as f64), (sx as f64, sy as f64))
+    }).collect();
+
+    /* find the transform most matches agree with */
+    let (homography, inliers) = ransac_homography(&pairs)?;
+
+    /* project the query image's (or region of interest's) corners into the search image,
+       keypoints and so the homography are in resized query image coordinates */
+    let [qsx, qsy] = query.scale();
+    let [x, y, w, h] = roi.unwrap_or([0.0, 0.0, query.original_dims[0] as f32, query.original_dims[1] as f32]);
+    let (x0, y0, x1, y1) = ((x / qsx) as f64, (y / qsy) as f64, ((x + w) / qsx) as f64, ((y + h) / qsy) as f64);
+    let query_corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+
+    let [sx_scale, sy_scale] = search.scale();
+    let [sx_offset, sy_offset] = search.offset;
+    let mut corners: [(f32, f32); 4] = [(0.0, 0.0); 4];
+    for (i, pt) in query_corners.iter().enumerate() {
+        let (x, y) = project(&homography, *pt)?;
+        corners[i] = ((x * sx_scale as f64) as f32 + sx_offset as f32, (y * sy_scale as f64) as f32 + sy_offset as f32);
+    }
+
+    /* reject twisted or collapsed outlines */
+    if !is_convex(&corners) {
+        return None
+    }
+
+    /* bounding box, clamped to the matched region of the search image */
+    let [sw, sh] = search.original_dims;
+    let (xlo, ylo) = (sx_offset as f32, sy_offset as f32);
+    let (xhi, yhi) = (xlo + sw as f32, ylo + sh as f32);
+    let xmin = corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min).max(xlo);
+    let ymin = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min).max(ylo);
+    let xmax = corners.iter().map(|c| c.0).fold(f32::NEG_INFINITY, f32::max).min(xhi);
+    let ymax = corners.iter().map(|c| c.1).fold(f32::NEG_INFINITY, f32::max).min(yhi);
+
+    if xmax <= xmin || ymax <= ymin {
+        return None
+    }
+
+    Some(Location {
+        corners,
+        bbox: [xmin, ymin, xmax - xmin, ymax - ymin],
+        num_inliers: inliers.len() as u32
+    })
+}
+
+/// robustly fits a homography to the point pairs, returns it along with the inlier indices
+fn ransac_homography(pairs: &Vec<((f64, f64), (f64, f64))>) -> Option<(Homography, Vec<usize>)> {
+
+    let mut rng = XorShift::new(pairs.len() as u64);
+    let mut best_inliers: Vec<usize> = Vec::new();
+
+    for _ in 0..RANSAC_ITERATIONS {
+
+        /* pick 4 distinct correspondences */
+        let mut sample: [usize; 4] = [0; 4];
+        let mut n = 0;
+        while n < 4 {
+            let idx = rng.below(pairs.len());
+            if !sample[..n].contains(&idx) {
+                sample[n] = idx;
+                n += 1;
+            }
+        }
+
+        let sample_pairs: Vec<((f64, f64), (f64, f64))> = sample.iter().map(|i| pairs[*i]).collect();
+        let h = match fit_homography(&sample_pairs) {
+            Some(h) => h,
+            None => continue
+        };
+
+        let inliers = find_inliers(&h, pairs);
+        if inliers.len() > best_inliers.len() {
+            best_inliers = inliers;
+        }
+    }
+
+    if best_inliers.len() < MIN_INLIERS {
+        return None
+    }
+
+    /* refit using every inlier */
+    let inlier_pairs: Vec<((f64, f64), (f64, f64))> = best_inliers.iter().map(|i| pairs[*i]).collect();
+    let h = fit_homography(&inlier_pairs)?;
+    let inliers = find_inliers(&h, pairs);
+
+    match inliers.len() >= MIN_INLIERS {
+        true => Some((h, inliers)),
+        false => None
+    }
+}
+
+/// indices of pairs whose reprojection error is under the inlier threshold
+fn find_inliers(h: &Homography, pairs: &Vec<((f64, f64), (f64, f64))>) -> Vec<usize> {
+
+    let thresh_sq = RANSAC_INLIER_THRESH * RANSAC_INLIER_THRESH;
+
+    pairs.iter().enumerate().filter_map(|(i, (src, dst))| {
+        match project(h, *src) {
+            Some((x, y)) if (x - dst.0).powi(2) + (y - dst.1).powi(2) < thresh_sq => Some(i),
+            _ => None
+        }
+    }).collect()
+}
+
+/// least squares homography fit (direct linear transform with h33 = 1),
+/// needs at least 4 pairs
+fn fit_homography(pairs: &Vec<((f64, f64), (f64, f64))>) -> Option<Homography> {
+
+    if pairs.len() < 4 {
+        return None
+    }
+
+    /* build normal equations (A^T A) h = A^T b */
+    let mut ata = [[0.0f64; 8]; 8];
+    let mut atb = [0.0f64; 8];
+
+    for ((x, y), (u, v)) in pairs.iter() {
+        let rows: [([f64; 8], f64); 2] = [
+            ([*x, *y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], *u),
+            ([0.0, 0.0, 0.0, *x, *y, 1.0, -v * x, -v * y], *v)
+        ];
+
+        for (row, rhs) in rows.iter() {
+            for i in 0..8 {
+                atb[i] += row[i] * rhs;
+                for j in 0..8 {
+                    ata[i][j] += row[i] * row[j];
+                }
+            }
+        }
+    }
+
+    let sol = solve_8x8(ata, atb)?;
+
+    Some([sol[0], sol[1], sol[2], sol[3], sol[4], sol[5], sol[6], sol[7], 1.0])
+}
+
+/// gaussian elimination with partial pivoting
+fn solve_8x8(mut a: [[f64; 8]; 8], mut b: [f64; 8]) -> Option<[f64; 8]> {
+
+    for col in 0..8 {
+
+        /* find pivot */
+        let pivot = (col..8).max_by(|i, j| a[*i][col].abs().total_cmp(&a[*j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-10 {
+            return None
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        /* eliminate below */
+        for row in (col + 1)..8 {
+            let factor = a[row][col] / a[col][col];
+            for k in col..8 {
+                a[row][k] -= factor * a[col][k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    /* back substitution */
+    let mut x = [0.0f64; 8];
+    for row in (0..8).rev() {
+        let mut sum = b[row];
+        for k in (row + 1)..8 {
+            sum -= a[row][k] * x[k];
+        }
+        x[row] = sum / a[row][row];
+    }
+
+    match x.iter().all(|v| v.is_finite()) {
+        true => Some(x),
+        false => None
+    }
+}
+
+/// applies homography to a point, None if the point maps to infinity or behind the camera
+fn project(h: &Homography, pt: (f64, f64)) -> Option<(f64, f64)> {
+
+    let (x, y) = pt;
+    let w = h[6] * x + h[7] * y + h[8];
+
+    if w <= 1e-10 {
+        return None
+    }
+
+    Some(((h[0] * x + h[1] * y + h[2]) / w, (h[3] * x + h[4] * y + h[5]) / w))
+}
+
+/// true if the quadrilateral is convex and has non-zero area
+fn is_convex(corners: &[(f32, f32); 4]) -> bool {
+
+    let mut sign = 0.0f32;
+
+    for i in 0..4 {
+        let (ax, ay) = corners[i];
+        let (bx, by) = corners[(i + 1) % 4];
+        let (cx, cy) = corners[(i + 2) % 4];
+        let cross = (bx - ax) * (cy - by) - (by - ay) * (cx - bx);
+
+        if cross.abs() < 1e-6 || !cross.is_finite() {
+            return false
+        }
+        if sign == 0.0 {
+            sign = cross.signum();
+        } else if cross.signum() != sign {
+            return false
+        }
+    }
+
+    true
+}
@@ -1,146 +1,487 @@
 /* my modules */
 /* ---------- */
 mod args;
-use args::ReverseImageSearchArgs;
+use args::{CacheCommand, CollectionCommand, Command, ConfigCommand, ReverseImageSearchArgs};
 
-mod cache;
+mod eval;
+use eval::{run_eval, EVAL_STEPS};
 
-mod utils;
+#[cfg(feature = "gui")]
 use rfd::FileDialog;
 // use image::DynamicImage;
-use sled::Db;
-use utils::{
-    load_config,
-    find_image_files
+use local_reverse_image_search::utils::{
+    discover_image_files,
+    lock
 };
+use local_reverse_image_search::actions::{act_on_matches, write_list_file, ActionStatus, Collision};
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::collections::{create_collection, drop_collection, list_collections, open_collection, Collection};
+use local_reverse_image_search::config::Config;
+use local_reverse_image_search::error::{Error, Failure, EXIT_OK, EXIT_PARTIAL};
+use local_reverse_image_search::layers::{load_layered, ConfigSources};
+use local_reverse_image_search::validation::{check_layered, report, validate};
+use local_reverse_image_search::region::load_mask;
+use local_reverse_image_search::utils::{Discovery, DiscoveryError};
 
-mod config;
-// use config::Config;
-
-mod feature_matching;
-use feature_matching::*;
+use local_reverse_image_search::feature_matching::*;
 
 /* 3rd party modules */
 /* ----------------- */
 use clap::Parser;
+use serde_derive::Serialize;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::ExitCode;
 use std::time::Instant;
-use console::style;
+use console::{style, StyledObject};
 // use statrs::distribution::Normal;
-use statistical::{mean, standard_deviation};
-use std::sync::{Arc, Mutex};
 use unicode_segmentation::UnicodeSegmentation;
 
+/// everything that couldn't be read during a search, written with --failure-log
+#[derive(Serialize)]
+struct FailureLog<'a> {
+    failures: &'a Vec<Failure>,
+    discovery_errors: &'a Vec<DiscoveryError>
+}
+
+/// name the query image goes by in messages when it's read from stdin
+const STDIN_NAME: &str = "<stdin>";
+
+/// where the query image comes from
+enum QueryImg {
+    File(String),
+    Stdin(Vec<u8>)
+}
+
+/// one search: of the config's search directories, or of a collection
+struct Search {
+    /// None for the config's search directories
+    collection: Option<String>,
+    config: Config,
+    info: Vec<ImgInfo>,
+    failures: Vec<Failure>,
+    discovery: Discovery
+}
+
+/// asks for query images with a file dialog, None if cancelled
+#[cfg(feature = "gui")]
+fn pick_query_imgs() -> Result<Option<Vec<String>>, Error> {
+    println!("please select one or more query image files (views of the same object), click cancel to quit");
+    Ok(FileDialog::new().set_directory(".").pick_files().map(|paths| paths.iter().map(|path| path.to_string_lossy().to_string()).collect()))
+}
+
+#[cfg(not(feature = "gui"))]
+fn pick_query_imgs() -> Result<Option<Vec<String>>, Error> {
+    Err(Error::Usage { message: "no query image given and this build has no file dialog (gui feature), pass --query-img-path (- reads it from stdin)".to_string() })
+}
+
+/// asks a yes/no question on the terminal, anything but yes (or no answer at all) is no
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = io::Write::flush(&mut io::stdout());
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false
+    }
+}
+
+/// runs the search (or eval), exits with one of the codes described in error.rs
 // #[show_image::main]
-fn main() {
+/// "[n/num_steps]", labels the steps of a run
+fn step(n: usize, num_steps: usize) -> StyledObject<String> {
+    style(format!("[{}/{}]", n, num_steps)).bold().green()
+}
+
+fn main() -> ExitCode {
+
+    match run() {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            println!("\n{}: {}", style("ERROR").bold().bright().red(), err);
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+fn run() -> Result<u8, Error> {
 
     /* parse command line args */
     let args = ReverseImageSearchArgs::parse();
 
+    /* defaults, user and project config files, profile, environment and --set, in that order */
+    let sources = ConfigSources {
+        user_file: None,
+        project_file: args.config_file_path.clone(),
+        profile: args.profile.clone(),
+        env: std::env::vars().collect(),
+        overrides: args.set.clone()
+    };
+
+    /* only inspecting the config */
+    if let Some(Command::Config(config_args)) = &args.command {
+        let mut layered = load_layered(&sources)?;
+        let files = match layered.files.is_empty() { true => "none".to_string(), false => layered.files.join(", ") };
+        match config_args.command {
+            ConfigCommand::Show => {
+                println!("# config files: {}", files);
+                print!("{}", layered.show());
+            },
+            ConfigCommand::Check => {
+                let problems = check_layered(&mut layered);
+                for problem in problems.iter() {
+                    println!("{}: {}", style("ERROR").bold().bright().red(), problem);
+                }
+                if !problems.is_empty() {
+                    let s_or_not: &str = match problems.len() { 1 => "", _ => "s" };
+                    return Err(Error::Config { message: format!("{} problem{} found (config files: {})", problems.len(), s_or_not, files) })
+                }
+                println!("config ok (config files: {})", files);
+            }
+        }
+        return Ok(EXIT_OK)
+    }
+
+    /* managing collections */
+    if let Some(Command::Collection(collection_args)) = &args.command {
+        let layered = load_layered(&sources)?;
+        let cache = Cache::open(&layered.config.cache_path)?;
+        match &collection_args.command {
+            CollectionCommand::Create { name, dirs, option } => {
+                let collection = Collection::new(dirs, option)?;
+                collection.config(name, &layered.config)?;
+                create_collection(&cache, name, &collection)?;
+                println!("collection {} created with {} search directories", style(name).bold(), collection.search_dirs_paths.len());
+            },
+            CollectionCommand::List => {
+                let collections = list_collections(&cache)?;
+                if collections.is_empty() {
+                    println!("no collections, add one with collection create <name> <dirs>...");
+                }
+                for (name, collection) in collections.iter() {
+                    let (_, collection_cache) = open_collection(&cache, name)?;
+                    println!("{} ({} cached images)", style(name).bold(), collection_cache.len());
+                    for root in collection.search_dirs_paths.iter() {
+                        println!("    {}", root.path());
+                    }
+                    for option in collection.options() {
+                        println!("    {}", style(option).dim());
+                    }
+                }
+            },
+            CollectionCommand::Drop { name } => match drop_collection(&cache, name)? {
+                true => println!("collection {} dropped", style(name).bold()),
+                false => return Err(Error::Usage { message: format!("there's no collection named {}, see collection list", name) })
+            }
+        }
+        return Ok(EXIT_OK)
+    }
+
+    /* maintaining the cache */
+    if let Some(Command::Cache(cache_args)) = &args.command {
+        let layered = load_layered(&sources)?;
+        let cache = Cache::open(&layered.config.cache_path)?;
+        match cache_args.command {
+            CacheCommand::Compact => {
+                let mut caches = vec![("cache".to_string(), cache.store().compact()?)];
+                for (name, _) in list_collections(&cache)? {
+                    let (_, collection_cache) = open_collection(&cache, &name)?;
+                    caches.push((format!("collection {}", name), collection_cache.store().compact()?));
+                }
+                for (name, (before, after)) in caches.iter() {
+                    println!("{}: {:.1} MiB -> {:.1} MiB ({} dead blocks removed)", style(name).bold(),
+                             before.bytes() as f64 / (1 << 20) as f64, after.bytes() as f64 / (1 << 20) as f64, before.dead_blocks());
+                }
+            }
+        }
+        return Ok(EXIT_OK)
+    }
+
     /* load config */
-    println!("\n{} loading config...", style("[1/4]").bold().green());
-    let config = load_config(&args.config_file_path);
+    let num_steps = match args.command {
+        Some(Command::Eval(_)) => EVAL_STEPS,
+        _ => 4
+    };
+    println!("\n{} loading config...", step(1, num_steps));
+    let mut layered = load_layered(&sources)?;
 
-    /* verify that some number of search paths were specified in config file */
-    if config.search_dirs_paths.len() == 0 {
-        println!("{}: no search paths specified, please enter some in {}", style("ERROR").bold().bright().red(), style(&args.config_file_path).bold());
-        return
+    /* every problem with the config at once, rather than one at a time deep in the search,
+       collections bring their own search directories (checked along with their settings) */
+    let mut problems = check_layered(&mut layered);
+    if !args.collection.is_empty() {
+        problems.retain(|problem| !problem.key.starts_with("search_dirs_paths"));
     }
+    report("the config", &problems)?;
+    let mut config = layered.config;
 
-    /* get query image path */
-    println!("\n{} loading query image...", style("[2/4]").bold().green());
-    let query_img_path: String = match args.query_img_path {
-        Some(path) => {
-            path.clone()
+    /* the command line's result options (--top-k, --path-glob, ...) are checked like the config's */
+    args.apply_to(&mut config)?;
+    let mut problems = validate(&mut config);
+    if !args.collection.is_empty() {
+        problems.retain(|problem| !problem.key.starts_with("search_dirs_paths"));
+    }
+    report("the command line options", &problems)?;
+
+    /* run evaluation instead of a search if requested */
+    if let Some(Command::Eval(eval_args)) = &args.command {
+        return run_eval(&config, eval_args)
+    }
+
+    /* get query image paths */
+    println!("\n{} loading query image...", step(2, num_steps));
+    let query_paths = match args.query_img_path.is_empty() {
+        false => args.query_img_path.clone(),
+        true if args.no_gui => {
+            return Err(Error::Usage { message: "no query image given, pass --query-img-path (- reads it from stdin)".to_string() })
         },
-        None => {
-            println!("please select a query image file, click cancel to quit");
-
-            match FileDialog::new().set_directory(".").pick_file() {
-                Some(path) => path.to_string_lossy().to_string(),
-                None => {
-                    println!("no file selected, quitting");
-                    return
-                }
+        true => match pick_query_imgs()? {
+            Some(paths) if !paths.is_empty() => paths,
+            _ => {
+                println!("no file selected, quitting");
+                return Ok(EXIT_OK)
             }
         }
     };
+    if query_paths.iter().filter(|path| *path == "-").count() > 1 {
+        return Err(Error::Usage { message: "stdin (-) can only be given once as a query image".to_string() })
+    }
+
+    let mut query_imgs = Vec::new();
+    for path in query_paths.into_iter() {
+        query_imgs.push(match path == "-" {
+            true => {
+                let mut bytes = Vec::new();
+                io::stdin().read_to_end(&mut bytes).map_err(|source| Error::Io { path: STDIN_NAME.to_string(), source })?;
+                QueryImg::Stdin(bytes)
+            },
+            false => QueryImg::File(path)
+        });
+    }
+    let query_names: Vec<String> = query_imgs.iter().map(|query_img| match query_img {
+        QueryImg::File(path) => path.clone(),
+        QueryImg::Stdin(_) => STDIN_NAME.to_string()
+    }).collect();
+
+    /* parts of the query image to search for, all of it if none are given */
+    let mut roi = Vec::new();
+    roi.extend(args.roi.clone());
+    if let Some(mask_path) = &args.roi_mask {
+        roi.extend(load_mask(mask_path)?);
+    }
+    if !roi.is_empty() && query_imgs.len() > 1 {
+        return Err(Error::Usage { message: "a region of interest is in one query image's pixels, give a single query image with --roi or --roi-mask".to_string() })
+    }
 
 
     /* start a timer */
     let timer: Instant = Instant::now();
 
     /* create new cache instance */
-    let cache: Arc<Mutex<Db>> = Arc::new(Mutex::new(sled::open(&config.cache_path).unwrap()));
+    let cache = Cache::open(&config.cache_path)?;
 
-    /* get info for query img */
-    let (_, desc_query) = match extract_single(cache.clone(),config.resize_dimensions.clone(), &query_img_path) {
-        Some((kp_query, desc_query, _)) => (kp_query, desc_query),
-        None => {
-            println!("{} -- unable to open file: {}", style("ERROR").bold().bright().red(), query_img_path);
-            return
+    /* what gets searched: the config's search directories, or each collection on its own,
+       with its own settings and its own part of the cache */
+    let mut targets: Vec<(Option<String>, Config, Cache)> = Vec::new();
+    if args.collection.is_empty() {
+        targets.push((None, config.clone(), cache.clone()));
+    }
+    for name in args.collection.iter() {
+        if targets.iter().any(|(collection, _, _)| collection.as_ref() == Some(name)) {
+            continue
         }
-    };
+        let (collection, collection_cache) = open_collection(&cache, name)?;
+        let mut collection_config = collection.config(name, &config)?;
+        args.apply_to(&mut collection_config)?;
+        report(&format!("collection {} with the command line options", name), &validate(&mut collection_config))?;
+        targets.push((Some(name.clone()), collection_config, collection_cache));
+    }
 
-    /* get all image file paths in search directories */
-    println!("\n{} exploring {} search directories...", style("[3/4]").bold().green(), &config.search_dirs_paths.len());
-    let img_paths = find_image_files(&config, &config.search_dirs_paths);
+    if query_names.len() > 1 {
+        println!("searching for {} query images together, scored by {} (query_score)", query_names.len(), config.query_score);
+    }
 
-    /* verify that non-zero number of images were found */
-    if img_paths.len() == 0 {
-            println!("{}: no images found in search paths", style("ERROR").bold().bright().red());
-            return
+    let mut searches: Vec<Search> = Vec::new();
+    for (collection, config, search_cache) in targets.into_iter() {
+
+        let in_collection = collection.as_ref().map_or(String::new(), |name| format!(" in collection {}", style(name).bold()));
+
+        /* get info for query imgs (with the settings of what's searched, but cached outside any
+           collection, which only hold their own images), views of several images are searched for together */
+        let mut queries: Vec<Vec<QueryView>> = Vec::new();
+        for query_img in query_imgs.iter() {
+            queries.push(match query_img {
+                QueryImg::File(path) => extract_query(&cache, &config, path)?,
+                QueryImg::Stdin(bytes) => extract_query_bytes(&config, bytes, &STDIN_NAME.to_string())?
+            });
+        }
+        let mut query = merge_queries(queries);
+        if !roi.is_empty() {
+            restrict_query(&mut query, &roi)?;
+        }
+
+        /* explore search directories, matching starts on images as soon as they're discovered */
+        println!("\n{} exploring {} search directories{}...", step(3, num_steps), &config.search_dirs_paths.len(), in_collection);
+        let discovery_stream = discover_image_files(&config, &config.search_dirs_paths, config.print_live_analysis_results)?;
+        let discovery_arc = discovery_stream.summary();
+
+        /* get info for search imgs */
+        println!("\n{} finding matching points in images{}...", step(4, num_steps), in_collection);
+        let (mut info, failures) = calculate_similarities(&search_cache, &config, &query, discovery_stream)?;
+
+        /* discovery is complete once matching has drained the stream */
+        let mut discovery = std::mem::take(&mut *lock(&discovery_arc));
+        discovery.sort();
+        discovery.print_summary();
+
+        /* most matches first, ties broken by path so the order doesn't depend on thread timing */
+        info.sort_by(|a, b| b.num_matches.cmp(&a.num_matches).then_with(|| a.path.cmp(&b.path)));
+
+        searches.push(Search { collection, config, info, failures, discovery });
     }
 
-    /* get info for search imgs */
-    println!("\n\n{} finding matching points in images...", style("[4/4]").bold().green());
-    let (info_search_arc, failed_paths) = calculate_similarities(cache.clone(), &config, &desc_query, img_paths);
+    /* matches of each search, grouped by collection */
+    let mut no_images = false;
+    let mut groups: Vec<(&Search, Vec<&str>)> = Vec::new();
+    for search in searches.iter() {
+
+        if let Some(name) = &search.collection {
+            let topstr = format!("==== collection {} ====", style(name).bold());
+            println!("\n{}", topstr);
+        }
+
+        /* verify that non-zero number of images were found */
+        if search.discovery.img_paths.len() == 0 {
+            println!("{}: no images found in search paths", style("ERROR").bold().bright().red());
+            no_images = true;
+            continue
+        }
+
+        /* filter matches from list, then limit and sort them as asked for */
+        let (mean, stddev, matches) = select_results(&search.info, &search.config)?;
+        println!("num matches --> mean: {}, std dev: {}", mean, stddev);
+
+        /* print images that couldn't be processed and why */
+        let failures = &search.failures;
+        let s_or_not: &str = match failures.len() { 1 => "", _ => "s" };
+        let topstr = format!("----{} image{} failed ----", style(failures.len()).bold(), s_or_not); 
+        println!("\n{}", topstr);
+        for failure in failures.iter() {
+            println!("{} ({}): {}", style(&failure.path).bold().red(), style(failure.kind).bold(), failure.message);
+        }
+        // println!("----");
+        println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
+
+        /* print entries that couldn't be read while exploring search directories */
+        let errors = &search.discovery.errors;
+        if errors.len() > 0 {
+            let s_or_not: &str = match errors.len() { 1 => "y", _ => "ies" };
+            let topstr = format!("----{} unreadable entr{} in search directories ----", style(errors.len()).bold(), s_or_not);
+            println!("\n{}", topstr);
+            for err in errors.iter() {
+                println!("{} ({}): {}", style(&err.path).bold().red(), style(err.kind).bold(), err.message);
+            }
+            println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
+        }
+
+        /* print matches */
+        let s_or_not: &str = match matches.len() { 1 => "", _ => "ES" };
+        let topstr = format!("----{} MATCH{}----", style(matches.len()).bold(), s_or_not);
+        println!("\n{}", topstr);
+        for m in matches.iter() {
 
-    let mut info_search = info_search_arc.lock().unwrap();
+            let (z, info) = m;
 
-    info_search.sort_by_key(|x| x.num_matches);
-    info_search.reverse();
+            println!("{} -> {}: {:.2}, {}: {}",
+                                        style(info.path.clone()).bold().bright().color256(42),
+                                        style("z-score").bold().bright(), z,
+                                        style("matches").bold().bright(), info.num_matches);
 
-    /* calculate mean and std dev of distances */
-    let nmatches_list: Vec<f32> = info_search.iter().map(|x| x.num_matches as f32).collect();
-    let mean = mean(&nmatches_list);
-    let stddev = standard_deviation(&nmatches_list, Some(mean));
-    println!("num matches --> mean: {}, std dev: {}", mean, stddev);
+            /* which of several query images matched best */
+            if query_names.len() > 1 {
+                println!("    {} -> {}", style("query").bold().bright(), query_names[info.source]);
+            }
+
+            /* note when a mirrored copy of the query is what matched */
+            if info.orientation != Orientation::Original {
+                println!("    {} -> {}", style("orientation").bold().bright(), info.orientation);
+            }
 
-    /* filter matches from list */
-    let mut matches: Vec<(f32, &ImgInfo)> = Vec::new();
-    for entry in info_search.iter() {
-        let z: f32 = (entry.num_matches as f32 - mean) / stddev;
-            if z > config.outlier_zscore_thresh {
-                matches.push((z, entry));
+            /* print where the query was found in the matched image */
+            if let Some(location) = &info.location {
+                println!("    {} -> {}, {}: {}",
+                                        style("location").bold().bright(), location,
+                                        style("inliers").bold().bright(), location.num_inliers);
             }
+
+        }
+        // println!("----");
+        println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
+
+        groups.push((search, matches.iter().map(|(_, info)| info.path.as_str()).collect()));
     }
 
-    /* print failed paths */
-    let s_or_not: &str = match failed_paths.len() { 1 => "", _ => "s" };
-    let topstr = format!("----{} image{} failed to open ----", style(failed_paths.len()).bold(), s_or_not); 
-    println!("\n{}", topstr);
-    for fp in failed_paths {
-        println!("{}", style(fp).bold().red());
+    println!("\ndone in {:?}", timer.elapsed());
+
+    /* gather the matches */
+    let num_matches: usize = groups.iter().map(|(_, sources)| sources.len()).sum();
+    if let Some(list_path) = &args.list_file {
+        match args.dry_run {
+            true => println!("would write {} matches to {}", num_matches, style(list_path).bold()),
+            false => {
+                let sources: Vec<&str> = groups.iter().flat_map(|(_, sources)| sources.iter().copied()).collect();
+                write_list_file(list_path, &sources)?;
+                println!("matches written to {}", style(list_path).bold());
+            }
+        }
     }
-    // println!("----");
-    println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
-    
-    /* print matches */
-    let s_or_not: &str = match matches.len() { 1 => "", _ => "ES" };
-    let topstr = format!("----{} MATCH{}----", style(matches.len()).bold(), s_or_not);
-    println!("\n{}", topstr);
-    for m in matches.iter() {
 
-        let (z, info) = m;
-        
-        println!("{} -> {}: {:.2}, {}: {}",
-                                    style(info.path.clone()).bold().bright().color256(42),
-                                    style("z-score").bold().bright(), z,
-                                    style("matches").bold().bright(), info.num_matches);
+    let mut actions_failed = false;
+    if let (Some(action), Some(target_dir)) = (args.action, &args.target_dir) {
+
+        /* moving takes files out of the search directories, overwriting replaces whatever was there */
+        let destructive = action.is_destructive() || args.on_collision == Collision::Overwrite;
+        let question = format!("{} {} matches to {} (existing files: {})?", action, num_matches, target_dir, args.on_collision);
+        if num_matches == 0 || (destructive && !args.dry_run && !args.yes && !confirm(&question)) {
+            println!("nothing to {}", action);
+        } else {
+            let verb = match args.dry_run {
+                true => format!("would {}", action),
+                false => action.to_string()
+            };
 
+            /* each collection's matches keep their paths below its own search directories */
+            for (search, sources) in groups.iter() {
+                let outcomes = act_on_matches(action, args.on_collision, args.dry_run, &search.config.search_dirs_paths, Path::new(target_dir), sources);
+                for outcome in outcomes.iter() {
+                    match &outcome.status {
+                        ActionStatus::Done { target } => println!("{} {} -> {}", verb, outcome.source, target.display()),
+                        ActionStatus::Skipped { target } => println!("{} {}, {} exists", style("skipped").bold().yellow(), outcome.source, target.display()),
+                        ActionStatus::Failed { message } => {
+                            actions_failed = true;
+                            println!("{}: {}: {}", style("ERROR").bold().bright().red(), outcome.source, message);
+                        }
+                    }
+                }
+            }
+        }
     }
-    // println!("----");
-    println!("{}", "-".repeat(topstr.graphemes(true).count()-8));
 
-    println!("\ndone in {:?}", timer.elapsed());
-}
\ No newline at end of file
+    /* the search itself worked, but let scripts know not everything could be read */
+    let failures: Vec<Failure> = searches.iter().flat_map(|search| search.failures.iter().cloned()).collect();
+    let discovery_errors: Vec<DiscoveryError> = searches.iter().flat_map(|search| search.discovery.errors.iter().cloned()).collect();
+
+    /* keep a record of failures for later if asked to */
+    if let Some(log_path) = &args.failure_log {
+        let log = FailureLog { failures: &failures, discovery_errors: &discovery_errors };
+        match toml::to_string(&log).map(|s| fs::write(log_path, s)) {
+            Ok(Ok(_)) => println!("failures written to {}", style(log_path).bold()),
+            _ => println!("{}: unable to write failure log to {}", style("WARNING").bold().yellow(), log_path)
+        }
+    }
+
+    match failures.len() + discovery_errors.len() + actions_failed as usize + no_images as usize {
+        0 => Ok(EXIT_OK),
+        _ => Ok(EXIT_PARTIAL)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::error::{Error, Result};
+
+use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// an area of an image, in original (full size) image pixel coordinates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Region {
+    /// x, y of the top left corner, width and height
+    Rect { x: f32, y: f32, w: f32, h: f32 },
+
+    /// vertices in order, the last one connects back to the first
+    Polygon { points: Vec<[f32; 2]> }
+}
+
+impl Region {
+
+    /// whether the point is inside the region (polygons use the even-odd rule)
+    pub fn contains(&self, [px, py]: [f32; 2]) -> bool {
+        match self {
+            Region::Rect { x, y, w, h } => px >= *x && px < x + w && py >= *y && py < y + h,
+            Region::Polygon { points } => {
+                let mut inside = false;
+                for (i, [x1, y1]) in points.iter().enumerate() {
+                    let [x2, y2] = points[(i + 1) % points.len()];
+                    if (*y1 > py) != (y2 > py) && px < x1 + (py - y1) * (x2 - x1) / (y2 - y1) {
+                        inside = !inside;
+                    }
+                }
+                inside
+            }
+        }
+    }
+
+    /// bounding rectangle --> [x, y, width, height]
+    pub fn bounds(&self) -> [f32; 4] {
+        match self {
+            Region::Rect { x, y, w, h } => [*x, *y, *w, *h],
+            Region::Polygon { points } => {
+                let x0 = points.iter().map(|p| p[0]).fold(f32::INFINITY, f32::min);
+                let y0 = points.iter().map(|p| p[1]).fold(f32::INFINITY, f32::min);
+                let x1 = points.iter().map(|p| p[0]).fold(f32::NEG_INFINITY, f32::max);
+                let y1 = points.iter().map(|p| p[1]).fold(f32::NEG_INFINITY, f32::max);
+                [x0, y0, x1 - x0, y1 - y0]
+            }
+        }
+    }
+
+    /// what's wrong with the region if it can't contain anything
+    pub(crate) fn validate(&self) -> std::result::Result<(), String> {
+        match self {
+            Region::Rect { w, h, .. } if *w <= 0.0 || *h <= 0.0 => Err(format!("region {} has no area", self)),
+            Region::Polygon { points } if points.len() < 3 => Err(format!("polygon {} needs at least 3 points", self)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// "x,y,w,h", as given to --roi
+impl FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Region, String> {
+
+        let values: Vec<f32> = s.split(',')
+                                .map(|v| v.trim().parse::<f32>())
+                                .collect::<std::result::Result<_, _>>()
+                                .map_err(|err| format!("expected x,y,w,h: {}", err))?;
+
+        let region = match values[..] {
+            [x, y, w, h] => Region::Rect { x, y, w, h },
+            _ => return Err(format!("expected x,y,w,h, got {} values", values.len()))
+        };
+
+        region.validate()?;
+        Ok(region)
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Rect { x, y, w, h } => write!(f, "{},{} {}x{}", x, y, w, h),
+            Region::Polygon { points } => {
+                let points: Vec<String> = points.iter().map(|[x, y]| format!("{},{}", x, y)).collect();
+                write!(f, "[{}]", points.join(" "))
+            }
+        }
+    }
+}
+
+/// a mask file: regions that together make up the mask
+#[derive(Debug, Deserialize)]
+struct MaskFile {
+    regions: Vec<Region>
+}
+
+/// reads the regions of a mask file (toml), e.g.
+/// ```toml
+/// [[regions]]
+/// points = [[120, 40], [380, 40], [400, 260], [100, 260]]
+///
+/// [[regions]]
+/// x = 0
+/// y = 0
+/// w = 64
+/// h = 64
+/// ```
+pub fn load_mask(filepath: &str) -> Result<Vec<Region>> {
+
+    let data = fs::read_to_string(filepath)
+                    .map_err(|err| Error::Config { message: format!("unable to read mask file {}: {}", filepath, err) })?;
+    let mask: MaskFile = toml::from_str(&data)
+                    .map_err(|err| Error::Config { message: format!("unable to parse mask file {}: {}", filepath, err) })?;
+
+    for region in mask.regions.iter() {
+        region.validate().map_err(|message| Error::Config { message: format!("mask file {}: {}", filepath, message) })?;
+    }
+
+    Ok(mask.regions)
+}
@@ -0,0 +1,377 @@
+use crate::cache::MyKeyPoint;
+use crate::config::ExtractParams;
+use crate::error::{self, Error};
+use crate::utils::lock;
+
+use memmap2::Mmap;
+use serde_derive::{Serialize, Deserialize};
+use sled::{Batch, Db, Tree};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// size of one akaze descriptor, every descriptor is stored as one block of this many bytes
+pub const DESCRIPTOR_BYTES: usize = 64;
+
+/// a single packed descriptor
+pub type Block = [u8; DESCRIPTOR_BYTES];
+
+/// file (inside the cache directory) holding the descriptor blocks of every image back to back
+pub const BLOCKS_FILE: &str = "descriptors.bin";
+
+/// sled tree with the offset table, keyed by path
+pub const OFFSETS_TREE: &str = "descriptor_offsets";
+
+/// one region (the whole image or a tile) of a packed entry: where its descriptors
+/// are in the block file, plus everything else matching and localization need,
+/// so warm searches never have to deserialize the full cache entry
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PackedRegion {
+    pub original_dims: [u32; 2],
+    pub resized_dims: [u32; 2],
+    pub offset: [u32; 2],
+    pub keypoints: Vec<MyKeyPoint>,
+    first_block: u64,
+    num_blocks: u64
+}
+
+/// offset table record for one image
+#[derive(Debug, Serialize, Deserialize)]
+struct PackedEntry {
+    tile_grid_sizes: Vec<u32>,
+    params: ExtractParams,
+    regions: Vec<PackedRegion>
+}
+
+/// an image's features packed into blocks but not stored yet,
+/// region offsets are relative to the start of its own blocks until appended
+#[derive(Debug)]
+pub struct PackedFeatures {
+    entry: PackedEntry,
+    blocks: Vec<Block>
+}
+
+impl PackedFeatures {
+
+    pub fn new(tile_grid_sizes: &Vec<u32>, params: &ExtractParams) -> PackedFeatures {
+        PackedFeatures { entry: PackedEntry { tile_grid_sizes: tile_grid_sizes.clone(), params: *params, regions: Vec::new() }, blocks: Vec::new() }
+    }
+
+    /// adds a region, keypoints and descriptors have to line up
+    pub fn push_region<I>(&mut self, original_dims: [u32; 2], resized_dims: [u32; 2], offset: [u32; 2], keypoints: Vec<MyKeyPoint>, descriptors: I)
+    where
+        I: IntoIterator<Item = Block>
+    {
+        let first_block = self.blocks.len();
+        self.blocks.extend(descriptors);
+        let num_blocks = (self.blocks.len() - first_block) as u64;
+
+        self.entry.regions.push(PackedRegion { original_dims, resized_dims, offset, keypoints, first_block: first_block as u64, num_blocks });
+    }
+
+    pub fn tile_grid_sizes(&self) -> &Vec<u32> {
+        &self.entry.tile_grid_sizes
+    }
+
+    /// what the features were extracted with
+    pub fn params(&self) -> &ExtractParams {
+        &self.entry.params
+    }
+
+    /// each region along with its descriptors
+    pub fn regions(&self) -> impl Iterator<Item = (&PackedRegion, &[Block])> {
+        self.entry.regions.iter().map(|r| (r, &self.blocks[r.first_block as usize..(r.first_block + r.num_blocks) as usize]))
+    }
+}
+
+/// a packed entry read back from the store, its descriptors are borrowed straight from the mapped block file
+pub struct MappedFeatures {
+    entry: PackedEntry,
+    map: Arc<Mmap>
+}
+
+impl MappedFeatures {
+
+    pub fn tile_grid_sizes(&self) -> &Vec<u32> {
+        &self.entry.tile_grid_sizes
+    }
+
+    /// what the features were extracted with
+    pub fn params(&self) -> &ExtractParams {
+        &self.entry.params
+    }
+
+    /// each region along with its descriptors, nothing is copied
+    pub fn regions(&self) -> impl Iterator<Item = (&PackedRegion, &[Block])> {
+        self.entry.regions.iter().map(|r| {
+            let start = r.first_block as usize * DESCRIPTOR_BYTES;
+            (r, as_blocks(&self.map[start..start + r.num_blocks as usize * DESCRIPTOR_BYTES]))
+        })
+    }
+}
+
+/// dead blocks (left behind by replaced images) over which compact_if_wasteful compacts the
+/// block file, once they're also more than the live ones (65536 blocks is 4 MiB)
+const MIN_DEAD_BLOCKS: u64 = 1 << 16;
+
+/// how much of the block file the offset table still points at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StoreUsage {
+    pub live_blocks: u64,
+    pub total_blocks: u64
+}
+
+impl StoreUsage {
+
+    /// blocks no record points at anymore
+    pub fn dead_blocks(&self) -> u64 {
+        self.total_blocks.saturating_sub(self.live_blocks)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.total_blocks * DESCRIPTOR_BYTES as u64
+    }
+}
+
+/// packed, memory mapped descriptors of every cached image: one append-only file of
+/// fixed size blocks plus an offset table in the cache db. Lookups share the current
+/// mapping without copying, appended blocks become visible once the store is remapped
+/// (see remap), so a run only reads what previous runs packed. Replaced images leave
+/// their old blocks behind until the file is compacted (see compact)
+pub struct DescriptorStore {
+    path: PathBuf,
+    file: Mutex<File>,
+    map: RwLock<Option<Arc<Mmap>>>,
+    offsets: Tree,
+
+    /* set when an append replaced a record, leaving its old blocks dead */
+    replaced: AtomicBool
+}
+
+impl DescriptorStore {
+
+    /// opens (or creates) the block file in dir, next to the offset table in db, prefix
+    /// keeps the file and table apart from other stores in the same db (e.g. a collection's)
+    pub fn open(db: &Db, dir: &Path, prefix: &str) -> error::Result<DescriptorStore> {
+
+        let path = dir.join(format!("{}{}", prefix, BLOCKS_FILE));
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path)
+                        .map_err(|err| store_error(&path, err))?;
+        let offsets = db.open_tree(format!("{}{}", prefix, OFFSETS_TREE))?;
+
+        let store = DescriptorStore { path, file: Mutex::new(file), map: RwLock::new(None), offsets, replaced: AtomicBool::new(false) };
+        store.remap()?;
+
+        Ok(store)
+    }
+
+    /// the packed features for path, None if there aren't any or they aren't in the
+    /// current mapping (appended since, or the block file was truncated or replaced)
+    pub fn get(&self, path: &str) -> error::Result<Option<MappedFeatures>> {
+
+        let val = match self.offsets.get(path)? {
+            Some(val) => val,
+            None => return Ok(None)
+        };
+
+        /* unreadable records are treated like no record, the image just gets packed again */
+        let entry: PackedEntry = match bincode::deserialize(&val) {
+            Ok(entry) => entry,
+            Err(_) => return Ok(None)
+        };
+
+        let map = match self.map.read().unwrap_or_else(|err| err.into_inner()).as_ref() {
+            Some(map) => Arc::clone(map),
+            None => return Ok(None)
+        };
+
+        Ok(if entry.fits(map.len() as u64) { Some(MappedFeatures { entry, map }) } else { None })
+    }
+
+    /// appends the blocks of each image and points its offset table record at them,
+    /// blocks are synced to disk before the records are written, so a record never
+    /// points at blocks that aren't there. Replaced images leave their old blocks behind
+    /// until the file is compacted
+    pub fn append(&self, entries: Vec<(String, PackedFeatures)>) -> error::Result<()> {
+
+        if entries.is_empty() {
+            return Ok(())
+        }
+
+        let mut records = Batch::default();
+        {
+            let mut file = lock(&self.file);
+            let len = file.metadata().map_err(|err| store_error(&self.path, err))?.len();
+
+            /* a crash can leave a partial block at the end, pad it so blocks stay aligned */
+            let padding = (DESCRIPTOR_BYTES - len as usize % DESCRIPTOR_BYTES) % DESCRIPTOR_BYTES;
+            let mut bytes: Vec<u8> = vec![0; padding];
+            let mut next_block = (len + padding as u64) / DESCRIPTOR_BYTES as u64;
+
+            for (path, mut features) in entries {
+
+                for region in features.entry.regions.iter_mut() {
+                    region.first_block += next_block;
+                }
+                next_block += features.blocks.len() as u64;
+                bytes.extend(features.blocks.iter().flatten());
+
+                if self.offsets.contains_key(path.as_bytes())? {
+                    self.replaced.store(true, Ordering::Relaxed);
+                }
+                let record = bincode::serialize(&features.entry).map_err(|err| Error::Cache { message: err.to_string() })?;
+                records.insert(path.as_bytes(), record);
+            }
+
+            file.write_all(&bytes).and_then(|_| file.sync_data()).map_err(|err| store_error(&self.path, err))?;
+        }
+
+        self.offsets.apply_batch(records)?;
+        Ok(())
+    }
+
+    /// maps the block file as it is now, lookups already holding the old mapping keep it
+    pub fn remap(&self) -> error::Result<()> {
+        let file = lock(&self.file);
+        self.map_file(&file)
+    }
+
+    fn map_file(&self, file: &File) -> error::Result<()> {
+
+        let len = file.metadata().map_err(|err| store_error(&self.path, err))?.len();
+
+        /* empty files can't be mapped */
+        let map = if len == 0 {
+            None
+        } else {
+            /* SAFETY: the block file is only ever appended to (by this process, sled's lock on
+               the cache db keeps other processes out) or replaced by compact with a new file,
+               which leaves the old one's bytes as they were, so mapped bytes never change under us */
+            let map = unsafe { Mmap::map(file) }.map_err(|err| store_error(&self.path, err))?;
+            Some(Arc::new(map))
+        };
+
+        *self.map.write().unwrap_or_else(|err| err.into_inner()) = map;
+        Ok(())
+    }
+
+    /// how many blocks the file has and how many of them the offset table points at
+    pub fn usage(&self) -> error::Result<StoreUsage> {
+        let file = lock(&self.file);
+        self.usage_of(&file)
+    }
+
+    fn usage_of(&self, file: &File) -> error::Result<StoreUsage> {
+
+        let len = file.metadata().map_err(|err| store_error(&self.path, err))?.len();
+        let mut live_blocks = 0;
+        for item in self.offsets.iter() {
+            let (_, val) = item?;
+            match bincode::deserialize::<PackedEntry>(&val) {
+                Ok(entry) if entry.fits(len) => live_blocks += entry.regions.iter().map(|r| r.num_blocks).sum::<u64>(),
+                _ => ()
+            }
+        }
+
+        Ok(StoreUsage { live_blocks, total_blocks: len / DESCRIPTOR_BYTES as u64 })
+    }
+
+    /// compacts the block file if appends replaced records since the last call and the dead
+    /// blocks are over MIN_DEAD_BLOCKS and more than the live ones, returns whether it did
+    pub fn compact_if_wasteful(&self) -> error::Result<bool> {
+
+        if !self.replaced.swap(false, Ordering::Relaxed) {
+            return Ok(false)
+        }
+
+        let usage = self.usage()?;
+        if usage.dead_blocks() < MIN_DEAD_BLOCKS || usage.dead_blocks() <= usage.live_blocks {
+            return Ok(false)
+        }
+
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// rewrites the block file with only the blocks the offset table points at, returns the
+    /// usage before and after. The records are cleared before the new file replaces the old
+    /// one and written again after, so a crash in between loses records (the images just get
+    /// packed again) but never points one at the wrong blocks. Records that don't fit the file
+    /// or can't be read are dropped
+    pub fn compact(&self) -> error::Result<(StoreUsage, StoreUsage)> {
+
+        let mut file = lock(&self.file);
+        let before = self.usage_of(&file)?;
+        if before.total_blocks == 0 {
+            return Ok((before, before))
+        }
+
+        /* SAFETY: as in map_file, and nothing appends while the file lock is held */
+        let old = unsafe { Mmap::map(&*file) }.map_err(|err| store_error(&self.path, err))?;
+
+        let tmp_path = self.path.with_extension("bin.compact");
+        let tmp = File::create(&tmp_path).map_err(|err| store_error(&tmp_path, err))?;
+        let mut writer = BufWriter::new(&tmp);
+        let mut records = Batch::default();
+        let mut next_block = 0;
+
+        for item in self.offsets.iter() {
+            let (key, val) = item?;
+            let mut entry: PackedEntry = match bincode::deserialize(&val) {
+                Ok(entry) if entry.fits(old.len() as u64) => entry,
+                _ => continue
+            };
+
+            for region in entry.regions.iter_mut() {
+                let start = region.first_block as usize * DESCRIPTOR_BYTES;
+                let end = start + region.num_blocks as usize * DESCRIPTOR_BYTES;
+                writer.write_all(&old[start..end]).map_err(|err| store_error(&tmp_path, err))?;
+                region.first_block = next_block;
+                next_block += region.num_blocks;
+            }
+
+            let record = bincode::serialize(&entry).map_err(|err| Error::Cache { message: err.to_string() })?;
+            records.insert(key, record);
+        }
+
+        writer.flush().map_err(|err| store_error(&tmp_path, err))?;
+        drop(writer);
+        tmp.sync_all().map_err(|err| store_error(&tmp_path, err))?;
+
+        self.offsets.clear()?;
+        self.offsets.flush()?;
+        fs::rename(&tmp_path, &self.path).map_err(|err| store_error(&self.path, err))?;
+        *file = OpenOptions::new().read(true).append(true).open(&self.path).map_err(|err| store_error(&self.path, err))?;
+
+        /* lookups get the new mapping before any record points into it */
+        self.map_file(&file)?;
+        self.offsets.apply_batch(records)?;
+        self.offsets.flush()?;
+
+        Ok((before, StoreUsage { live_blocks: next_block, total_blocks: next_block }))
+    }
+}
+
+impl PackedEntry {
+
+    /// whether every region lies within a block file of len bytes and has a block per keypoint
+    fn fits(&self, len: u64) -> bool {
+        let num_blocks = len / DESCRIPTOR_BYTES as u64;
+        self.regions.iter().all(|r| {
+            r.first_block.checked_add(r.num_blocks).map_or(false, |end| end <= num_blocks) && r.keypoints.len() as u64 == r.num_blocks
+        })
+    }
+}
+
+fn store_error(path: &Path, err: io::Error) -> Error {
+    Error::Cache { message: format!("descriptor store {}: {}", path.display(), err) }
+}
+
+/// views packed bytes as descriptor blocks, trailing bytes that don't fill a block are left out
+fn as_blocks(bytes: &[u8]) -> &[Block] {
+    /* SAFETY: a block is a plain byte array (alignment 1), so any run of bytes is a valid
+       run of blocks, and the length is rounded down to whole blocks */
+    unsafe { std::slice::from_raw_parts(bytes.as_ptr() as *const Block, bytes.len() / DESCRIPTOR_BYTES) }
+}
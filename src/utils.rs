@@ -1,107 +1,527 @@
-use crate::config::Config;
+use crate::config::{Config, ScanOptions, SearchRoot};
+use crate::error::{Error, Result};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::sync::{Arc, Mutex};
-use std::thread::{self, JoinHandle};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
 use std::path::Path;
-use walkdir::WalkDir;
-use std::fs;
+use walkdir::{DirEntry, WalkDir};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs::{self, File};
+use std::io::{Cursor, Read};
+use image::{DynamicImage, ImageFormat, ImageResult};
+use image::io::Reader;
 // use  native_dialog::FileDialog;
 use console::style;
+use serde_derive::{Serialize, Deserialize};
+use std::fmt;
+use std::io;
 
-/// returns true if path leads to image file,
-/// returns false otherwise
-pub fn is_valid_file(valid_extensions: Vec<String>, path: &Path) -> bool {
+/// tiny deterministic rng (xorshift64) for anything that needs repeatable "randomness"
+pub struct XorShift(u64);
 
-    /* return true if is file and ends with img format */
-    if path.is_file() {
-        
-        let s = path.to_string_lossy().to_lowercase();
+impl XorShift {
+    pub fn new(seed: u64) -> XorShift {
+        XorShift(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+    }
 
-        for ext in valid_extensions {
-            if s.ends_with(ext.as_str()) {
-                return true
-            }
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// random number in [0, n)
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+/// locks a mutex, carrying on with the data if another thread panicked while holding it
+/// (everything shared between workers stays valid between statements) rather than
+/// letting one panic take down every other thread
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// counting semaphore, limits how many threads do something at once
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar
+}
+
+/// a permit taken from a Semaphore, given back when dropped
+pub struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Semaphore {
+
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore { permits: Mutex::new(permits), available: Condvar::new() }
+    }
+
+    /// blocks until a permit is free
+    pub fn acquire(&self) -> SemaphorePermit<'_> {
+        let mut permits = lock(&self.permits);
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap_or_else(PoisonError::into_inner);
+        }
+        *permits -= 1;
+        SemaphorePermit(self)
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        *lock(&self.0.permits) += 1;
+        self.0.available.notify_one();
+    }
+}
+
+/// how a file found while exploring the search directories gets treated
+#[derive(Debug, PartialEq)]
+pub enum FileKind {
+    Image,
+
+    /// not an image according to its extension (or content, when detecting by content)
+    Skipped,
+
+    /// has an image extension but its content isn't a recognized image format
+    Unrecognized
+}
+
+/// results of exploring the search directories
+#[derive(Debug, Default)]
+pub struct Discovery {
+    pub img_paths: Vec<String>,
+
+    /// number of files left out because they aren't images or were filtered out by the scan options
+    /// (including symlinks when they aren't followed)
+    pub num_skipped: usize,
+
+    /// files with an image extension whose content isn't a recognized image format
+    pub unrecognized: Vec<String>,
+
+    /// images reached more than once through overlapping search roots or symlinks
+    pub num_duplicates: usize,
+
+    /// entries that couldn't be read while exploring
+    pub errors: Vec<DiscoveryError>
+}
+
+impl Discovery {
+
+    /// sorts every list of paths so results don't depend on thread timing
+    pub fn sort(&mut self) {
+        self.img_paths.sort();
+        self.unrecognized.sort();
+        self.errors.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    /// prints counts of what was found, listing unrecognized files since they'd otherwise fail later
+    pub fn print_summary(&self) {
+
+        println!("{} images found, {} other files skipped, {} unrecognized, {} duplicates ignored, {} errors",
+                                    style(self.img_paths.len()).bold(),
+                                    style(self.num_skipped).bold(),
+                                    style(self.unrecognized.len()).bold(),
+                                    style(self.num_duplicates).bold(),
+                                    style(self.errors.len()).bold());
+
+        for p in self.unrecognized.iter() {
+            println!("{}: {}", style("unrecognized image").bold().yellow(), style(p).bold().red());
         }
     }
+}
+
+/// what went wrong reading an entry while exploring the search directories
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DiscoveryErrorKind {
+    PermissionDenied,
+    NotFound,
+    BrokenSymlink,
+    SymlinkLoop,
+    Other
+}
+
+impl fmt::Display for DiscoveryErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiscoveryErrorKind::PermissionDenied => write!(f, "permission denied"),
+            DiscoveryErrorKind::NotFound => write!(f, "not found"),
+            DiscoveryErrorKind::BrokenSymlink => write!(f, "broken symlink"),
+            DiscoveryErrorKind::SymlinkLoop => write!(f, "symlink loop"),
+            DiscoveryErrorKind::Other => write!(f, "error")
+        }
+    }
+}
 
-    /* return false otherwise */
-    false
+/// an entry that couldn't be read while exploring the search directories
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryError {
+    /// the failing entry (the search root itself if walkdir doesn't say)
+    pub path: String,
+    pub kind: DiscoveryErrorKind,
+    pub message: String
 }
 
-pub fn find_image_files(config: &Config, dir_paths: &Vec<String>) -> Vec<String> {
+impl DiscoveryError {
+
+    fn from_walkdir(root: &str, err: &walkdir::Error) -> DiscoveryError {
+
+        let path = match err.path() {
+            Some(p) => p.to_string_lossy().to_string(),
+            None => root.to_string()
+        };
+
+        let kind = match (err.loop_ancestor(), err.io_error().map(|e| e.kind())) {
+            (Some(_), _) => DiscoveryErrorKind::SymlinkLoop,
+            (_, Some(io::ErrorKind::PermissionDenied)) => DiscoveryErrorKind::PermissionDenied,
+            (_, Some(io::ErrorKind::NotFound)) => match is_symlink(&path) {
+                true => DiscoveryErrorKind::BrokenSymlink,
+                false => DiscoveryErrorKind::NotFound
+            },
+            _ => DiscoveryErrorKind::Other
+        };
+
+        DiscoveryError { path, kind, message: err.to_string() }
+    }
+}
+
+impl fmt::Display for DiscoveryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.path, self.kind, self.message)
+    }
+}
+
+fn is_symlink(path: &str) -> bool {
+    fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false)
+}
+
+/// returns true if ext is one of valid_extensions, case insensitive and with or without a leading dot
+fn is_valid_extension(valid_extensions: &Vec<String>, ext: &str) -> bool {
+    valid_extensions.iter().any(|v| v.trim_start_matches('.').eq_ignore_ascii_case(ext))
+}
+
+/// returns true if path's extension (the whole extension, not just the end of the
+/// file name) is one of valid_extensions
+pub fn has_valid_extension(valid_extensions: &Vec<String>, path: &Path) -> bool {
+
+    match path.extension() {
+        Some(ext) => is_valid_extension(valid_extensions, &ext.to_string_lossy()),
+        None => false
+    }
+}
+
+/// guesses the image format from the file's magic bytes
+pub fn sniff_format(path: &Path) -> Option<ImageFormat> {
+
+    let mut header: [u8; 64] = [0; 64];
+    let mut f = File::open(path).ok()?;
+    let n = f.read(&mut header).ok()?;
+
+    image::guess_format(&header[..n]).ok()
+}
+
+/// decides whether path is an image to search, either by extension alone or,
+/// if detect_by_content is set, by its magic bytes (so misnamed and extension-less
+/// images are found, and non-images with image extensions are caught early)
+pub fn classify_file(valid_extensions: &Vec<String>, detect_by_content: bool, path: &Path) -> FileKind {
+
+    let valid_ext = has_valid_extension(valid_extensions, path);
+
+    if !detect_by_content {
+        return match valid_ext {
+            true => FileKind::Image,
+            false => FileKind::Skipped
+        }
+    }
 
-    let img_paths: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+    /* content must be a format whose extensions are in the valid list */
+    let content_ok = match sniff_format(path) {
+        Some(format) => format.extensions_str().iter().any(|e| is_valid_extension(valid_extensions, e)),
+        None => false
+    };
+
+    match (content_ok, valid_ext) {
+        (true, _) => FileKind::Image,
+        (false, true) => FileKind::Unrecognized,
+        (false, false) => FileKind::Skipped
+    }
+}
+
+/// opens an image, detecting its format from content rather than trusting the extension
+pub fn open_image(path: &str) -> ImageResult<DynamicImage> {
+    decode_image(path, &fs::read(path)?)
+}
+
+/// decodes an image file already read into memory, like open_image the format
+/// comes from the content, falling back to path's extension
+pub fn decode_image(path: &str, bytes: &[u8]) -> ImageResult<DynamicImage> {
+
+    let mut reader = Reader::new(Cursor::new(bytes));
+    if let Ok(format) = ImageFormat::from_path(path) {
+        reader.set_format(format);
+    }
+
+    reader.with_guessed_format()?.decode()
+}
+
+/// builds a gitignore style matcher for patterns relative to root
+pub(crate) fn build_glob_matcher(root: &str, patterns: &Vec<String>) -> Result<Gitignore> {
+
+    let invalid = |pattern: &str, err: ignore::Error| Error::Config { message: format!("invalid glob '{}' for {}: {}", pattern, root, err) };
+
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns.iter() {
+        builder.add_line(None, pattern).map_err(|err| invalid(pattern, err))?;
+    }
+
+    builder.build().map_err(|err| invalid(&patterns.join(", "), err))
+}
+
+/// true if a directory should be descended into
+fn keep_dir(entry: &DirEntry, skip_hidden: bool, excludes: &Gitignore) -> bool {
+
+    /* never filter out the search root itself */
+    if entry.depth() == 0 {
+        return true
+    }
+
+    if skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+        return false
+    }
+
+    !excludes.matched(entry.path(), true).is_ignore()
+}
+
+/// true if a file passes the include/exclude globs and size limits
+fn keep_file(entry: &DirEntry, scan: &ScanOptions, includes: &Gitignore, excludes: &Gitignore) -> bool {
+
+    if excludes.matched(entry.path(), false).is_ignore() {
+        return false
+    }
+
+    /* included if the file or any directory above it (below the root) matches */
+    if !scan.include.is_empty() {
+        let included = entry.path().ancestors()
+                            .take(entry.depth())
+                            .enumerate()
+                            .any(|(i, p)| includes.matched(p, i > 0).is_ignore());
+        if !included {
+            return false
+        }
+    }
+
+    if scan.min_file_size.is_some() || scan.max_file_size.is_some() {
+        let size = match entry.metadata() {
+            Ok(meta) => meta.len(),
+            Err(_) => return false
+        };
+        if size < scan.min_file_size.unwrap_or(0) || size > scan.max_file_size.unwrap_or(u64::MAX) {
+            return false
+        }
+    }
+
+    true
+}
+
+/// a file found while exploring the search directories
+enum DiscoveredFile {
+    Image(String),
+    Skipped,
+    Unrecognized(String),
+    Error(DiscoveryError)
+}
+
+/// image paths streamed from the search directories while they're being explored,
+/// paths are canonical and each file is yielded once even if search roots overlap,
+/// the order depends on how the per-root walks interleave
+pub struct DiscoveryStream {
+    receiver: Receiver<DiscoveredFile>,
+    seen: HashSet<String>,
+    seen_unrecognized: HashSet<String>,
+    summary: Arc<Mutex<Discovery>>
+}
+
+impl DiscoveryStream {
+
+    /// everything discovered so far, complete once the stream is exhausted
+    pub fn summary(&self) -> Arc<Mutex<Discovery>> {
+        self.summary.clone()
+    }
+}
+
+impl Iterator for DiscoveryStream {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let file = self.receiver.recv().ok()?;
+            let mut summary = lock(&self.summary);
+
+            match file {
+                DiscoveredFile::Image(p) => {
+                    if self.seen.insert(p.clone()) {
+                        summary.img_paths.push(p.clone());
+                        return Some(p)
+                    }
+                    summary.num_duplicates += 1;
+                },
+                DiscoveredFile::Skipped => summary.num_skipped += 1,
+                DiscoveredFile::Unrecognized(p) => {
+                    if self.seen_unrecognized.insert(p.clone()) {
+                        summary.unrecognized.push(p);
+                    }
+                },
+                DiscoveredFile::Error(err) => summary.errors.push(err)
+            }
+        }
+    }
+}
+
+/// starts exploring the search directories (one thread per root) and returns
+/// a stream of image paths, so extraction can start before the walk finishes,
+/// fails up front if any root's scan options are invalid
+pub fn discover_image_files(config: &Config, roots: &Vec<SearchRoot>, show_progress: bool) -> Result<DiscoveryStream> {
+
+    let (sender, receiver) = channel::<DiscoveredFile>();
 
     // println!("--------------------");
     // println!("searching in {:?}", dir_paths);
 
-    let mut handles: Vec<JoinHandle<()>> = Vec::new();
-    let m = MultiProgress::new();
+    let m = match show_progress {
+        true => MultiProgress::new(),
+        false => MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    };
     // let num_paths = dir_paths.len();
 
-    for _path in dir_paths {
+    /* build every root's globs before starting any walks, so bad patterns fail the whole run */
+    let mut walks = Vec::new();
+    for root in roots {
+        let path = root.path().clone();
+        let scan = root.scan_options(&config.scan);
+        let includes = build_glob_matcher(&path, &scan.include)?;
+        let excludes = build_glob_matcher(&path, &scan.exclude)?;
+        walks.push((root, path, scan, includes, excludes));
+    }
 
-        let path = String::from(_path);
+    for (root, path, scan, includes, excludes) in walks {
 
-        let this_img_paths = img_paths.clone();
+        let this_sender = sender.clone();
         let pb = m.add(ProgressBar::new_spinner());
         pb.set_style(ProgressStyle::with_template("{msg:24} {spinner} {prefix:.bold.dim}")
                                         .unwrap()
                                         .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ "));
         pb.set_prefix(format!("{}", path));
 
-        let valid_extensions = config.valid_file_extensions.clone();
+        let valid_extensions = root.settings(config).valid_file_extensions;
+        let detect_by_content = config.detect_file_type_by_content;
 
-        handles.push(thread::spawn(move || {
+        thread::spawn(move || {
 
             let mut num_files = 0;
 
-            for entry in WalkDir::new(&path) {
-                match entry {
-                    Ok(ref direntry) => if is_valid_file(valid_extensions.clone(), direntry.path()){
-                        // println!("{}", direntry.path().display());
-                        // pb.set_message(format!("{}", direntry.path().to_string_lossy()));
-                        pb.inc(1);
-                        let mut impaths = this_img_paths.lock().unwrap();
-                        impaths.push(direntry.path().to_string_lossy().to_string());
-                        drop(impaths);
-                        num_files += 1;
+            let skip_hidden = scan.skip_hidden.unwrap_or(false);
+
+            /* walkdir detects symlink loops itself when following links, reporting them as errors */
+            let walker = WalkDir::new(&path)
+                            .follow_links(scan.follow_symlinks.unwrap_or(false))
+                            .max_depth(scan.max_depth.unwrap_or(usize::MAX))
+                            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+                            .into_iter()
+                            .filter_entry(|e| !e.file_type().is_dir() || keep_dir(e, skip_hidden, &excludes));
+
+            for entry in walker {
+
+                let file = match entry {
+                    Ok(ref direntry) => {
+
+                        /* symlinks that aren't followed show up as entries of their own,
+                           report the ones pointing nowhere instead of quietly dropping them */
+                        if direntry.path_is_symlink() && fs::metadata(direntry.path()).is_err() {
+                            DiscoveredFile::Error(DiscoveryError {
+                                path: direntry.path().to_string_lossy().to_string(),
+                                kind: DiscoveryErrorKind::BrokenSymlink,
+                                message: "symlink target does not exist".to_string()
+                            })
+                        }
+                        /* file_type doesn't follow symlinks unless follow_links is set, so a
+                           symlinked file is only searched when symlinks are followed */
+                        else if !direntry.file_type().is_file() {
+                            match direntry.path_is_symlink() {
+                                true => DiscoveredFile::Skipped,
+                                false => continue
+                            }
+                        }
+                        else {
+
+                            /* canonical paths so the same file reached through overlapping roots
+                               or symlinks is only searched once, only for files that are kept
+                               since it's a syscall per path component */
+                            let canonical = || match fs::canonicalize(direntry.path()) {
+                                Ok(p) => p.to_string_lossy().to_string(),
+                                Err(_) => direntry.path().to_string_lossy().to_string()
+                            };
+
+                            match keep_file(direntry, &scan, &includes, &excludes) {
+                                false => DiscoveredFile::Skipped,
+                                true => match classify_file(&valid_extensions, detect_by_content, direntry.path()) {
+                                    FileKind::Image => {
+                                        // println!("{}", direntry.path().display());
+                                        // pb.set_message(format!("{}", direntry.path().to_string_lossy()));
+                                        pb.inc(1);
+                                        num_files += 1;
+                                        DiscoveredFile::Image(canonical())
+                                    },
+                                    FileKind::Skipped => DiscoveredFile::Skipped,
+                                    FileKind::Unrecognized => DiscoveredFile::Unrecognized(canonical())
+                                }
+                            }
+                        }
                     }
-                    Err(_) => println!("error opening file: {}", path)
+                    Err(ref err) => DiscoveredFile::Error(DiscoveryError::from_walkdir(&path, err))
+                };
+
+                /* receiver gone means nobody wants the rest */
+                if this_sender.send(file).is_err() {
+                    return
                 }
             }
 
             pb.finish_with_message(format!("{} files discovered", num_files));
-        }));
+        });
     }
 
-    for handle in handles {
-        let _ = handle.join();
-    }
+    Ok(DiscoveryStream { receiver, seen: HashSet::new(), seen_unrecognized: HashSet::new(), summary: Arc::new(Mutex::new(Discovery::default())) })
+}
+
+/// explores the search directories to completion, returns canonical,
+/// deduplicated paths in sorted order
+pub fn find_image_files(config: &Config, roots: &Vec<SearchRoot>) -> Result<Discovery> {
+
+    let mut stream = discover_image_files(config, roots, true)?;
+    let summary = stream.summary();
 
-    /* "unpack" strings from arc mutex */
-    let out = img_paths.lock().unwrap()
-                        .iter()
-                        .map(|s| s.clone())
-                        .collect();
+    /* drain the stream, it records everything in the summary as it goes */
+    for _ in stream.by_ref() {}
 
-    out
+    /* "unpack" discovery from arc mutex */
+    let mut out = std::mem::take(&mut *lock(&summary));
+    out.sort();
+
+    Ok(out)
 }
 
-pub fn load_config(filepath: &String) -> Config {
+/// reads and parses the config file
+pub fn load_config(filepath: &String) -> Result<Config> {
     
-    /* load config file as json string */
-    let data = fs::read_to_string(&filepath).expect("Unable to read config file");
-
-    /* parse json string into config struct */ 
-    match toml::from_str(&data) {
-        Ok(cfg) => cfg,
-        Err(err) => {
-            println!("----------------------");
-            println!("{} -- something went wrong opening the config file ({}).\nerror: {}", style("ERROR").bold().bright().red(), filepath, err);
-            println!("----------------------");
-            panic!();
-        }
-    }
+    /* load config file as toml string */
+    let data = fs::read_to_string(&filepath)
+                    .map_err(|err| Error::Config { message: format!("unable to read config file {}: {}", filepath, err) })?;
+
+    /* parse toml string into config struct */ 
+    toml::from_str(&data).map_err(|err| Error::Config { message: format!("unable to parse config file {}: {}", filepath, err) })
 }
@@ -0,0 +1,341 @@
+use crate::config::{Config, ExtractorOptions, ResultOptions, RootSettings, ScanOptions, SearchRoot};
+use crate::error::{Error, Result};
+use crate::layers::{flatten, LayeredConfig, Source};
+use crate::utils::build_glob_matcher;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use toml::value::Value;
+
+/// something wrong with one config value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// dotted key of the value, search directories are indexed, e.g. search_dirs_paths[1].mask
+    pub key: String,
+    pub message: String,
+
+    /// where the value was set, file:line if it was in a config file, None if that isn't known
+    pub location: Option<String>
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}: {}", location, self.key, self.message),
+            None => write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+
+impl Problem {
+    fn new(key: &str, message: String) -> Problem {
+        Problem { key: key.to_string(), message, location: None }
+    }
+}
+
+/// checks the values of a config, normalizing what can be normalized (extensions lose their leading
+/// dot and are lower cased), returns every problem found rather than stopping at the first
+pub fn validate(config: &mut Config) -> Vec<Problem> {
+
+    let mut problems: Vec<Problem> = Vec::new();
+    let mut problem = |key: &str, message: String| problems.push(Problem::new(key, message));
+
+    if let Some(message) = ratio_problem(config.ratio_test_ratio) {
+        problem("ratio_test_ratio", message);
+    }
+    if let Some(message) = resize_problem(config.resize_dimensions) {
+        problem("resize_dimensions", message);
+    }
+    if !config.outlier_zscore_thresh.is_finite() {
+        problem("outlier_zscore_thresh", format!("is {}, has to be a number", config.outlier_zscore_thresh));
+    }
+    if !(0.0..=1.0).contains(&config.stop_word_fraction) {
+        problem("stop_word_fraction", format!("is {}, has to be between 0 (disabled) and 1", config.stop_word_fraction));
+    }
+    if config.tile_grid_sizes.contains(&0) {
+        problem("tile_grid_sizes", format!("is {:?}, grids have to be at least 1 x 1", config.tile_grid_sizes));
+    }
+
+    if let Some(message) = extensions_problem(&mut config.valid_file_extensions) {
+        problem("valid_file_extensions", message);
+    }
+    for (key, message) in extractor_problems(&config.extractor) {
+        problem(key, message);
+    }
+
+    if fs::metadata(&config.cache_path).map_or(false, |meta| !meta.is_dir()) {
+        problem("cache_path", format!("{} exists and isn't a directory", config.cache_path));
+    }
+
+    if config.search_dirs_paths.is_empty() {
+        problem("search_dirs_paths", "is empty, there's nothing to search".to_string());
+    }
+    for (i, root) in config.search_dirs_paths.iter_mut().enumerate() {
+        let key = format!("search_dirs_paths[{}]", i);
+        if root.path().is_empty() {
+            problem(&format!("{}.path", key), "is missing, every search directory needs a path".to_string());
+            continue
+        }
+        match fs::metadata(root.path()) {
+            Ok(meta) if meta.is_dir() => {},
+            Ok(_) => problem(&key, format!("{} isn't a directory", root.path())),
+            Err(err) => problem(&key, format!("{} can't be read: {}", root.path(), err))
+        }
+        if let SearchRoot::Table { path, scan, settings } = root {
+            for (subkey, message) in scan_problems(path, scan) {
+                problem(&format!("{}.{}", key, subkey), message);
+            }
+            for (subkey, message) in root_settings_problems(settings) {
+                problem(&format!("{}.{}", key, subkey), message);
+            }
+        }
+    }
+
+    for (subkey, message) in scan_problems("/", &config.scan) {
+        problem(&format!("scan.{}", subkey), message);
+    }
+    for (subkey, message) in result_problems(&config.results) {
+        problem(&format!("results.{}", subkey), message);
+    }
+
+    problems
+}
+
+fn ratio_problem(ratio_test_ratio: f32) -> Option<String> {
+    match ratio_test_ratio > 0.0 && ratio_test_ratio <= 1.0 {
+        true => None,
+        false => Some(format!("is {}, has to be more than 0 and at most 1", ratio_test_ratio))
+    }
+}
+
+fn resize_problem(resize_dimensions: [u32; 2]) -> Option<String> {
+    match resize_dimensions.contains(&0) {
+        true => Some(format!("is {:?}, width and height have to be at least 1", resize_dimensions)),
+        false => None
+    }
+}
+
+/// takes the leading dot off extensions and lower cases them (they're compared
+/// without their dot and case insensitively anyway), dropping duplicates
+fn extensions_problem(extensions: &mut Vec<String>) -> Option<String> {
+    let mut seen: HashSet<String> = HashSet::new();
+    *extensions = extensions.iter()
+                    .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|ext| !ext.is_empty() && seen.insert(ext.clone()))
+                    .collect();
+    match extensions.is_empty() {
+        true => Some("has no extensions, no images would be found".to_string()),
+        false => None
+    }
+}
+
+/// (key, message) for each problem with extractor parameters
+fn extractor_problems(extractor: &ExtractorOptions) -> Vec<(&'static str, String)> {
+
+    let mut problems = Vec::new();
+
+    if let Some(threshold) = extractor.detector_threshold {
+        if !(threshold.is_finite() && threshold > 0.0) {
+            problems.push(("extractor.detector_threshold", format!("is {}, has to be more than 0", threshold)));
+        }
+    }
+    for (key, value) in [("extractor.max_octave_evolution", extractor.max_octave_evolution), ("extractor.num_sublevels", extractor.num_sublevels)] {
+        if value == Some(0) {
+            problems.push((key, "is 0, has to be at least 1".to_string()));
+        }
+    }
+
+    problems
+}
+
+/// (key, message) for each problem with a search directory's overrides, extensions are normalized in place
+fn root_settings_problems(settings: &mut RootSettings) -> Vec<(&'static str, String)> {
+
+    let mut problems = extractor_problems(&settings.extractor);
+
+    if let Some(message) = settings.ratio_test_ratio.and_then(ratio_problem) {
+        problems.push(("ratio_test_ratio", message));
+    }
+    if let Some(message) = settings.resize_dimensions.and_then(resize_problem) {
+        problems.push(("resize_dimensions", message));
+    }
+    if let Some(message) = settings.valid_file_extensions.as_mut().and_then(extensions_problem) {
+        problems.push(("valid_file_extensions", message));
+    }
+
+    problems
+}
+
+/// (key, message) for each problem with scan options
+fn scan_problems(root: &str, scan: &ScanOptions) -> Vec<(&'static str, String)> {
+
+    let mut problems = Vec::new();
+
+    for (key, globs) in [("include", &scan.include), ("exclude", &scan.exclude)] {
+        if let Err(err) = build_glob_matcher(root, globs) {
+            problems.push((key, err.to_string()));
+        }
+    }
+    if let (Some(min), Some(max)) = (scan.min_file_size, scan.max_file_size) {
+        if min > max {
+            problems.push(("min_file_size", format!("is {}, more than max_file_size ({}), no file would be searched", min, max)));
+        }
+    }
+    for region in scan.mask.iter() {
+        if let Err(message) = region.validate() {
+            problems.push(("mask", message));
+        }
+    }
+
+    problems
+}
+
+/// (key, message) for each problem with result options
+fn result_problems(results: &ResultOptions) -> Vec<(&'static str, String)> {
+
+    let mut problems = Vec::new();
+
+    if results.top_k == Some(0) {
+        problems.push(("top_k", "is 0, no matches would be reported".to_string()));
+    }
+    if let Err(err) = build_glob_matcher("/", &results.paths) {
+        problems.push(("paths", err.to_string()));
+    }
+
+    problems
+}
+
+/* options that are None by default don't show up when serialized, these have all of them set */
+fn full_scan() -> ScanOptions {
+    ScanOptions { max_depth: Some(0), follow_symlinks: Some(false), skip_hidden: Some(false), min_file_size: Some(0), max_file_size: Some(0), ..ScanOptions::default() }
+}
+
+fn full_extractor() -> ExtractorOptions {
+    ExtractorOptions { detector_threshold: Some(0.0), max_octave_evolution: Some(0), num_sublevels: Some(0) }
+}
+
+/// dotted keys of everything set in value
+fn keys_of<T: serde::Serialize>(value: T) -> HashSet<String> {
+    let mut keys = Vec::new();
+    if let Ok(Value::Table(table)) = Value::try_from(value) {
+        flatten(&table, "", &mut keys);
+    }
+    keys.into_iter().map(|(key, _)| key).collect()
+}
+
+/// every dotted key a config can have
+fn known_keys() -> HashSet<String> {
+    keys_of(Config {
+        scan: full_scan(),
+        results: ResultOptions { top_k: Some(0), min_matches: Some(0), ..ResultOptions::default() },
+        extractor: full_extractor(),
+        ..Config::default()
+    })
+}
+
+/// every dotted key a search directory table can have
+fn known_root_keys() -> HashSet<String> {
+    let settings = RootSettings { valid_file_extensions: Some(Vec::new()), resize_dimensions: Some([0, 0]), ratio_test_ratio: Some(0.0), extractor: full_extractor() };
+    keys_of(SearchRoot::Table { path: String::new(), scan: full_scan(), settings })
+}
+
+/// line (1 based) a dotted key is set on in a toml file, following [table] headers
+fn find_line(file: &str, key: &str) -> Option<usize> {
+
+    let data = fs::read_to_string(file).ok()?;
+    let mut table = String::new();
+
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let header = line.split('#').next().unwrap_or(line).trim();
+            table = header.trim_matches(|c| c == '[' || c == ']').trim().to_string();
+            continue
+        }
+
+        let name = match line.split_once('=') {
+            Some((name, _)) if !line.starts_with('#') => name.trim().trim_matches('"'),
+            _ => continue
+        };
+        let full = match table.is_empty() {
+            true => name.to_string(),
+            false => format!("{}.{}", table, name)
+        };
+        if key == full || key.starts_with(&format!("{}.", full)) {
+            return Some(i + 1)
+        }
+    }
+
+    None
+}
+
+/// where a value was set: file:line for config files (and profiles in them), else the source
+fn locate(layered: &LayeredConfig, key: &str) -> Option<String> {
+
+    /* search_dirs_paths[1].mask was set with search_dirs_paths */
+    let base = key.split('[').next().unwrap_or(key);
+
+    let (file, file_key) = match layered.source(base) {
+        Source::Default => return None,
+        Source::File(file) => (file, base.to_string()),
+        Source::Profile { name, file } => (file, format!("profile.{}.{}", name, base)),
+        source => return Some(source.to_string())
+    };
+
+    match find_line(&file, &file_key) {
+        Some(line) => Some(format!("{}:{}", file, line)),
+        None => Some(file)
+    }
+}
+
+/// every problem with a layered config, including keys that don't mean anything (most likely typos),
+/// each located in the file (and line) or the override it came from
+pub fn check_layered(layered: &mut LayeredConfig) -> Vec<Problem> {
+
+    let known = known_keys();
+    let mut keys: Vec<(String, String)> = Vec::new();
+    flatten(&layered.value, "", &mut keys);
+
+    /* search directory tables are in an array, which flattens to a single value */
+    if let Some(Value::Array(roots)) = layered.value.get("search_dirs_paths") {
+        let known_root = known_root_keys();
+        for (i, root) in roots.iter().enumerate() {
+            if let Value::Table(table) = root {
+                let mut root_keys = Vec::new();
+                flatten(table, "", &mut root_keys);
+                root_keys.retain(|(key, _)| !known_root.contains(key));
+                keys.extend(root_keys.into_iter().map(|(key, value)| (format!("search_dirs_paths[{}].{}", i, key), value)));
+            }
+        }
+    }
+
+    let mut problems: Vec<Problem> = keys.into_iter()
+                                        .filter(|(key, _)| !known.contains(key))
+                                        .map(|(key, _)| Problem::new(&key, "unknown key".to_string()))
+                                        .collect();
+    problems.extend(validate(&mut layered.config));
+
+    for problem in problems.iter_mut() {
+        problem.location = locate(layered, &problem.key);
+    }
+
+    problems
+}
+
+/// like check_layered, as a single config error listing every problem
+pub fn check(layered: &mut LayeredConfig) -> Result<()> {
+    report("the config", &check_layered(layered))
+}
+
+/// problems (with what, e.g. "the config") as a single config error listing every one of them, Ok if there are none
+pub fn report(what: &str, problems: &[Problem]) -> Result<()> {
+
+    if problems.is_empty() {
+        return Ok(())
+    }
+
+    let s_or_not = match problems.len() { 1 => "", _ => "s" };
+    let lines: Vec<String> = problems.iter().map(|problem| format!("  {}", problem)).collect();
+    Err(Error::Config { message: format!("{} problem{} found in {}\n{}", problems.len(), s_or_not, what, lines.join("\n")) })
+}
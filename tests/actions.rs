@@ -0,0 +1,90 @@
+/* result action tests: act_on_matches copies, links and moves matches into a target directory
+   keeping their paths below the search root, handles collisions and changes nothing in a dry run */
+
+use local_reverse_image_search::actions::{act_on_matches, write_list_file, Action, ActionOutcome, ActionStatus, Collision};
+use local_reverse_image_search::config::SearchRoot;
+use local_reverse_image_search::error::Error;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// a search root with search/a.png and search/sub/a.png, returns the root and the matches' canonical paths
+fn search_root(dir: &Path) -> (Vec<SearchRoot>, Vec<String>) {
+    fs::create_dir_all(dir.join("search").join("sub")).unwrap();
+    fs::write(dir.join("search").join("a.png"), "top").unwrap();
+    fs::write(dir.join("search").join("sub").join("a.png"), "sub").unwrap();
+
+    let root = fs::canonicalize(dir.join("search")).unwrap();
+    let sources = vec![root.join("a.png"), root.join("sub").join("a.png")];
+    (vec![SearchRoot::Path(root.to_string_lossy().to_string())], sources.iter().map(|p| p.to_string_lossy().to_string()).collect())
+}
+
+/// where each match went, None if it was skipped or failed
+fn targets(outcomes: &Vec<ActionOutcome>) -> Vec<Option<PathBuf>> {
+    outcomes.iter().map(|o| match &o.status {
+        ActionStatus::Done { target } => Some(target.clone()),
+        _ => None
+    }).collect()
+}
+
+#[test]
+fn matches_are_copied_linked_and_moved_with_their_structure() {
+
+    let dir = TempDir::new().unwrap();
+    let (roots, sources) = search_root(dir.path());
+    let sources: Vec<&str> = sources.iter().map(|s| s.as_str()).collect();
+    let out = dir.path().join("out");
+
+    /* a dry run only says where things would go */
+    let outcomes = act_on_matches(Action::Copy, Collision::Skip, true, &roots, &out, &sources);
+    assert_eq!(targets(&outcomes), vec![Some(out.join("a.png")), Some(out.join("sub").join("a.png"))]);
+    assert!(!out.exists());
+
+    let outcomes = act_on_matches(Action::Copy, Collision::Skip, false, &roots, &out, &sources);
+    assert_eq!(targets(&outcomes), vec![Some(out.join("a.png")), Some(out.join("sub").join("a.png"))]);
+    assert_eq!(fs::read_to_string(out.join("sub").join("a.png")).unwrap(), "sub");
+
+    /* collisions */
+    let outcomes = act_on_matches(Action::Copy, Collision::Skip, false, &roots, &out, &sources);
+    assert!(outcomes.iter().all(|o| matches!(o.status, ActionStatus::Skipped { .. })));
+
+    let outcomes = act_on_matches(Action::Copy, Collision::Rename, false, &roots, &out, &sources);
+    assert_eq!(targets(&outcomes), vec![Some(out.join("a_1.png")), Some(out.join("sub").join("a_1.png"))]);
+
+    fs::write(out.join("a.png"), "old").unwrap();
+    let outcomes = act_on_matches(Action::Hardlink, Collision::Overwrite, false, &roots, &out, &sources[..1]);
+    assert_eq!(targets(&outcomes), vec![Some(out.join("a.png"))]);
+    assert_eq!(fs::read_to_string(out.join("a.png")).unwrap(), "top");
+
+    #[cfg(unix)]
+    {
+        let linked = dir.path().join("linked");
+        act_on_matches(Action::Symlink, Collision::Skip, false, &roots, &linked, &sources);
+        assert_eq!(fs::read_link(linked.join("sub").join("a.png")).unwrap(), Path::new(sources[1]));
+    }
+
+    /* moving takes the matches out of the search root */
+    let moved = dir.path().join("moved");
+    let outcomes = act_on_matches(Action::Move, Collision::Skip, false, &roots, &moved, &sources);
+    assert_eq!(targets(&outcomes), vec![Some(moved.join("a.png")), Some(moved.join("sub").join("a.png"))]);
+    assert!(sources.iter().all(|s| !Path::new(s).exists()));
+    assert_eq!(fs::read_to_string(moved.join("a.png")).unwrap(), "top");
+
+    /* they're gone now */
+    let outcomes = act_on_matches(Action::Move, Collision::Skip, false, &roots, &dir.path().join("again"), &sources);
+    assert!(outcomes.iter().all(|o| matches!(o.status, ActionStatus::Failed { .. })));
+
+    let list = dir.path().join("matches.txt");
+    write_list_file(&list.to_string_lossy(), &sources).unwrap();
+    assert_eq!(fs::read_to_string(&list).unwrap(), format!("{}\n{}\n", sources[0], sources[1]));
+
+    /* an unwritable list file isn't mistaken for an unreadable query image */
+    let res = write_list_file(&dir.path().join("missing").join("matches.txt").to_string_lossy(), &sources);
+    assert!(matches!(res, Err(Error::Output { .. })), "{:?}", res.err());
+    assert_eq!(res.err().unwrap().exit_code(), 6);
+
+    assert_eq!("move".parse::<Action>(), Ok(Action::Move));
+    assert!("delete".parse::<Action>().is_err());
+    assert_eq!("rename".parse::<Collision>(), Ok(Collision::Rename));
+}
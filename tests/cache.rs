@@ -0,0 +1,203 @@
+/* descriptor store tests: warm searches read packed descriptors and give the same
+   results as cold ones, a lost block file falls back to the cache db, entries
+   extracted with other params (e.g. a search directory's overrides) are re-extracted,
+   compacting drops the blocks of replaced entries, extract_and_cache warms the cache
+   without matching */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::{Config, ExtractParams, ExtractorOptions, RootSettings, ScanOptions, SearchRoot};
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_and_cache, extract_query, extract_single};
+use local_reverse_image_search::store::BLOCKS_FILE;
+
+mod common;
+use common::test_config;
+
+use image::GenericImageView;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+/// writes a few crops of the query image and a mirrored copy, returns their paths
+fn write_search_images(dir: &Path) -> Vec<String> {
+
+    let img = image::open(QUERY_IMG_PATH).unwrap();
+    let (w, h) = img.dimensions();
+    let search = dir.join("search");
+    fs::create_dir_all(&search).unwrap();
+
+    let images = [
+        ("full.png", img.clone()),
+        ("left.png", img.crop_imm(0, 0, w / 2, h)),
+        ("corner.png", img.crop_imm(w / 3, h / 3, w / 2, h / 2)),
+        ("flipped.png", img.fliph())
+    ];
+
+    images.iter().map(|(name, img)| {
+        let path = search.join(name);
+        img.save(&path).unwrap();
+        path.to_string_lossy().to_string()
+    }).collect()
+}
+
+/// (path, num matches) of every searched image, sorted by path
+fn search(cache: &Cache, config: &Config, paths: &Vec<String>) -> Vec<(String, u32)> {
+
+    let query = extract_query(cache, config, &QUERY_IMG_PATH.to_string()).unwrap();
+    let (info, failures) = calculate_similarities(cache, config, &query, paths.clone()).unwrap();
+    assert!(failures.is_empty(), "{:?}", failures);
+
+    let mut res: Vec<(String, u32)> = info.into_iter().map(|i| (i.path, i.num_matches)).collect();
+    res.sort();
+    res
+}
+
+#[test]
+fn warm_searches_read_packed_descriptors() {
+
+    let dir = TempDir::new().unwrap();
+    let paths = write_search_images(dir.path());
+    let config = Config { tile_grid_sizes: vec![2], ..test_config(dir.path()) };
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    /* nothing packed before the first run */
+    assert!(paths.iter().all(|p| cache.store().get(p).unwrap().is_none()));
+
+    let cold = search(&cache, &config, &paths);
+    assert!(cold.iter().any(|(_, n)| *n > 0));
+
+    /* every image (whole image and its tiles) is packed and readable now */
+    for path in paths.iter() {
+        let mapped = cache.store().get(path).unwrap().expect("image wasn't packed");
+        assert_eq!(mapped.tile_grid_sizes(), &vec![2]);
+        assert!(mapped.regions().count() > 1);
+        assert!(mapped.regions().all(|(region, descriptors)| region.keypoints.len() == descriptors.len()));
+    }
+
+    let warm = search(&cache, &config, &paths);
+    assert_eq!(warm, cold);
+
+    /* and still after reopening */
+    drop(cache);
+    let cache = Cache::open(&config.cache_path).unwrap();
+    assert_eq!(search(&cache, &config, &paths), cold);
+}
+
+#[test]
+fn lost_block_file_falls_back_to_the_cache_db() {
+
+    let dir = TempDir::new().unwrap();
+    let paths = write_search_images(dir.path());
+    let config = Config { tile_grid_sizes: vec![2], ..test_config(dir.path()) };
+
+    let cache = Cache::open(&config.cache_path).unwrap();
+    let cold = search(&cache, &config, &paths);
+    drop(cache);
+
+    /* offset table still points into the (now empty) block file */
+    OpenOptions::new().write(true).open(dir.path().join(".cache").join(BLOCKS_FILE)).unwrap().set_len(0).unwrap();
+
+    let cache = Cache::open(&config.cache_path).unwrap();
+    assert!(paths.iter().all(|p| cache.store().get(p).unwrap().is_none()));
+    assert_eq!(search(&cache, &config, &paths), cold);
+
+    /* packed again */
+    assert!(paths.iter().all(|p| cache.store().get(p).unwrap().is_some()));
+}
+
+#[test]
+fn entries_extracted_with_other_params_are_re_extracted() {
+
+    let dir = TempDir::new().unwrap();
+    let paths = write_search_images(dir.path());
+    let mut config = Config { tile_grid_sizes: vec![2], ..test_config(dir.path()) };
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    let small = ExtractParams { resize_dims: [128, 128], ..ExtractParams::default() };
+    assert!(!extract_single(&cache, &ExtractParams::default(), &Vec::new(), &paths[0]).unwrap().1);
+    assert!(extract_single(&cache, &ExtractParams::default(), &Vec::new(), &paths[0]).unwrap().1);
+    assert!(!extract_single(&cache, &small, &Vec::new(), &paths[0]).unwrap().1);
+    assert!(extract_single(&cache, &small, &Vec::new(), &paths[0]).unwrap().1);
+
+    /* the search directory's overrides are what its images get extracted (and cached) with */
+    let root = fs::canonicalize(dir.path().join("search")).unwrap().to_string_lossy().to_string();
+    let settings = RootSettings {
+        resize_dimensions: Some([128, 128]),
+        extractor: ExtractorOptions { detector_threshold: Some(0.0005), ..ExtractorOptions::default() },
+        ..RootSettings::default()
+    };
+    config.search_dirs_paths = vec![SearchRoot::Table { path: root.clone(), scan: ScanOptions::default(), settings }];
+    let overridden = config.search_dirs_paths[0].settings(&config).extract;
+    assert_eq!(overridden, ExtractParams { resize_dims: [128, 128], extractor: ExtractorOptions { detector_threshold: Some(0.0005), ..ExtractorOptions::default() } });
+
+    let paths: Vec<String> = paths.iter().map(|p| fs::canonicalize(p).unwrap().to_string_lossy().to_string()).collect();
+    search(&cache, &config, &paths);
+    for path in paths.iter() {
+        assert_eq!(cache.get(path).unwrap().unwrap().params, overridden);
+        assert_eq!(cache.store().get(path).unwrap().unwrap().params(), &overridden);
+    }
+
+    /* and back to the global settings */
+    config.search_dirs_paths = vec![SearchRoot::Path(root)];
+    search(&cache, &config, &paths);
+    for path in paths.iter() {
+        assert_eq!(cache.get(path).unwrap().unwrap().params, config.settings().extract);
+    }
+}
+
+#[test]
+fn compacting_drops_the_blocks_of_replaced_entries() {
+
+    let dir = TempDir::new().unwrap();
+    let paths = write_search_images(dir.path());
+    let config = Config { tile_grid_sizes: vec![2], ..test_config(dir.path()) };
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    search(&cache, &config, &paths);
+    assert_eq!(cache.store().usage().unwrap().dead_blocks(), 0);
+
+    /* extracted again with other settings, the first blocks are left behind */
+    let config = Config { resize_dimensions: [256, 256], ..config };
+    let expected = search(&cache, &config, &paths);
+    let usage = cache.store().usage().unwrap();
+    assert!(usage.dead_blocks() > 0);
+
+    let (before, after) = cache.store().compact().unwrap();
+    assert_eq!(before, usage);
+    assert_eq!((after.live_blocks, after.dead_blocks()), (usage.live_blocks, 0));
+    assert_eq!(cache.store().usage().unwrap(), after);
+    assert_eq!(fs::metadata(dir.path().join(".cache").join(BLOCKS_FILE)).unwrap().len(), after.bytes());
+
+    /* the records point at the moved blocks */
+    assert!(paths.iter().all(|p| cache.store().get(p).unwrap().is_some()));
+    assert_eq!(search(&cache, &config, &paths), expected);
+
+    drop(cache);
+    let cache = Cache::open(&config.cache_path).unwrap();
+    assert_eq!(search(&cache, &config, &paths), expected);
+    assert_eq!(cache.store().usage().unwrap(), after);
+}
+
+#[test]
+fn extract_and_cache_packs_every_image_without_matching() {
+
+    let dir = TempDir::new().unwrap();
+    let mut paths = write_search_images(dir.path());
+    let config = Config { tile_grid_sizes: vec![2], ..test_config(dir.path()) };
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    let broken = dir.path().join("search").join("broken.png");
+    fs::write(&broken, "not an image").unwrap();
+    paths.push(broken.to_string_lossy().to_string());
+
+    let failures = extract_and_cache(&cache, &config, &paths).unwrap();
+    assert_eq!(failures.iter().map(|f| f.path.clone()).collect::<Vec<String>>(), vec![paths[4].clone()]);
+    assert_eq!(cache.len(), 4);
+    assert!(paths[..4].iter().all(|p| cache.store().get(p).unwrap().is_some()));
+
+    /* nothing left to do the second time */
+    let usage = cache.store().usage().unwrap();
+    extract_and_cache(&cache, &config, &paths[..4]).unwrap();
+    assert_eq!(cache.store().usage().unwrap(), usage);
+}
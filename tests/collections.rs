@@ -0,0 +1,99 @@
+/* collection tests: collections are created, listed and dropped by name, keep their own search
+   directories and settings (checked like the config's) and their own part of the cache */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::collections::{create_collection, drop_collection, list_collections, open_collection, Collection};
+use local_reverse_image_search::config::Config;
+use local_reverse_image_search::error::Error;
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_query};
+use local_reverse_image_search::utils::find_image_files;
+
+use image::GenericImageView;
+use std::fs;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+fn names(cache: &Cache) -> Vec<String> {
+    list_collections(cache).unwrap().into_iter().map(|(name, _)| name).collect()
+}
+
+#[test]
+fn collections_are_created_listed_and_dropped() {
+
+    let dir = TempDir::new().unwrap();
+    let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+    fs::create_dir_all(&a).unwrap();
+    fs::create_dir_all(&b).unwrap();
+    let (a, b) = (a.to_string_lossy().to_string(), b.to_string_lossy().to_string());
+    let cache = Cache::open(&dir.path().join(".cache").to_string_lossy()).unwrap();
+
+    let options = vec!["resize_dimensions=[512, 512]".to_string(), "extractor.detector_threshold=0.0005".to_string()];
+    let collection = Collection::new(&[a.clone()], &options).unwrap();
+    create_collection(&cache, "customer-a", &collection).unwrap();
+    create_collection(&cache, "customer-b", &Collection::new(&[b.clone()], &[]).unwrap()).unwrap();
+    assert_eq!(names(&cache), vec!["customer-a", "customer-b"]);
+
+    assert!(matches!(create_collection(&cache, "customer-a", &collection), Err(Error::Usage { .. })));
+    assert!(matches!(create_collection(&cache, "../customer-c", &collection), Err(Error::Usage { .. })));
+    assert!(matches!(Collection::new(&[a.clone()], &["num_workers=4".to_string()]), Err(Error::Usage { .. })));
+    assert!(matches!(Collection::new(&[dir.path().join("missing").to_string_lossy().to_string()], &[]), Err(Error::Usage { .. })));
+
+    /* its search directories and settings over the config's */
+    let (collection, collection_cache) = open_collection(&cache, "customer-a").unwrap();
+    let config = collection.config("customer-a", &Config::default()).unwrap();
+    assert_eq!(config.search_dirs_paths.iter().map(|root| root.path().clone()).collect::<Vec<String>>(), vec![fs::canonicalize(&a).unwrap().to_string_lossy().to_string()]);
+    assert_eq!(config.resize_dimensions, [512, 512]);
+    assert_eq!(config.extractor.detector_threshold, Some(0.0005));
+    assert_eq!(config.ratio_test_ratio, Config::default().ratio_test_ratio);
+    assert_eq!(collection.options(), vec!["extractor.detector_threshold = 0.0005", "resize_dimensions = [512, 512]"]);
+
+    let invalid = Collection::new(&[a.clone()], &["ratio_test_ratio=2".to_string()]).unwrap();
+    assert!(matches!(invalid.config("invalid", &Config::default()), Err(Error::Config { .. })));
+
+    /* each collection has its own part of the cache */
+    let (_, other_cache) = open_collection(&cache, "customer-b").unwrap();
+    collection_cache.insert("x.png", vec![1, 2, 3]).unwrap();
+    assert_eq!((collection_cache.len(), other_cache.len(), cache.len()), (1, 0, 0));
+    drop(collection_cache);
+
+    assert!(drop_collection(&cache, "customer-a").unwrap());
+    assert!(!drop_collection(&cache, "customer-a").unwrap());
+    assert!(matches!(open_collection(&cache, "customer-a"), Err(Error::Usage { .. })));
+    assert_eq!(names(&cache), vec!["customer-b"]);
+
+    /* created again it starts out empty */
+    create_collection(&cache, "customer-a", &collection).unwrap();
+    assert_eq!(open_collection(&cache, "customer-a").unwrap().1.len(), 0);
+}
+
+#[test]
+fn searching_a_collection_only_touches_its_part_of_the_cache() {
+
+    let dir = TempDir::new().unwrap();
+    let search = dir.path().join("search");
+    fs::create_dir_all(&search).unwrap();
+    let img = image::open(QUERY_IMG_PATH).unwrap();
+    let (w, h) = img.dimensions();
+    img.crop_imm(0, 0, w / 2, h).save(search.join("left.png")).unwrap();
+    img.fliph().save(search.join("flipped.png")).unwrap();
+
+    let base = Config { cache_path: dir.path().join(".cache").to_string_lossy().to_string(), print_live_analysis_results: false, ..Config::default() };
+    let cache = Cache::open(&base.cache_path).unwrap();
+    create_collection(&cache, "photos", &Collection::new(&[search.to_string_lossy().to_string()], &[]).unwrap()).unwrap();
+    create_collection(&cache, "other", &Collection::new(&[search.to_string_lossy().to_string()], &[]).unwrap()).unwrap();
+
+    let (collection, collection_cache) = open_collection(&cache, "photos").unwrap();
+    let config = collection.config("photos", &base).unwrap();
+    let query = extract_query(&cache, &config, &QUERY_IMG_PATH.to_string()).unwrap();
+    let img_paths = find_image_files(&config, &config.search_dirs_paths).unwrap().img_paths;
+    let (info, failures) = calculate_similarities(&collection_cache, &config, &query, img_paths).unwrap();
+    assert!(failures.is_empty(), "{:?}", failures);
+    assert_eq!(info.len(), 2);
+    assert!(info.iter().all(|i| collection_cache.store().get(&i.path).unwrap().is_some()));
+
+    /* both search images, the query is cached outside the collection */
+    assert_eq!(collection_cache.len(), 2);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(open_collection(&cache, "other").unwrap().1.len(), 0);
+}
@@ -0,0 +1,15 @@
+/* helpers shared by the integration tests and the benches */
+
+use local_reverse_image_search::config::{Config, SearchRoot};
+use std::path::Path;
+
+/// the default config, searching dir/search with the cache in dir/.cache and no per-image output,
+/// tests set whatever else they depend on
+pub fn test_config(dir: &Path) -> Config {
+    Config {
+        cache_path: dir.join(".cache").to_string_lossy().to_string(),
+        search_dirs_paths: vec![SearchRoot::Path(dir.join("search").to_string_lossy().to_string())],
+        print_live_analysis_results: false,
+        ..Config::default()
+    }
+}
@@ -0,0 +1,203 @@
+/* file discovery tests: extension matching, content based detection, scan filters, deduplication and error reporting in find_image_files */
+
+use local_reverse_image_search::config::{Config, RootSettings, ScanOptions, SearchRoot};
+use local_reverse_image_search::utils::{discover_image_files, find_image_files, DiscoveryErrorKind};
+
+mod common;
+use common::test_config;
+
+use image::{DynamicImage, ImageOutputFormat};
+use std::fs::{self, File};
+use std::path::Path;
+use tempfile::TempDir;
+
+/// writes a png (whatever the file name says) and a few decoys into dir/search
+fn write_files(dir: &Path) {
+
+    let search = dir.join("search");
+    fs::create_dir_all(&search).unwrap();
+
+    let img = DynamicImage::new_rgb8(16, 16);
+    for name in ["real.png", "upper.PNG", "photo.jpg", "no_extension", "foo.notpng"] {
+        let mut f = File::create(search.join(name)).unwrap();
+        img.write_to(&mut f, ImageOutputFormat::Png).unwrap();
+    }
+    fs::write(search.join("notes.txt"), "not an image").unwrap();
+    fs::write(search.join("fake.png"), "not an image either").unwrap();
+}
+
+fn file_names(paths: &Vec<String>) -> Vec<String> {
+    let mut names: Vec<String> = paths.iter()
+        .map(|p| Path::new(p).file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn extensions_are_matched_as_whole_extensions() {
+
+    let dir = TempDir::new().unwrap();
+    write_files(dir.path());
+    let config = Config { valid_file_extensions: vec!["png".to_string(), ".JPG".to_string()], ..test_config(dir.path()) };
+
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+
+    assert_eq!(file_names(&discovery.img_paths), vec!["fake.png", "photo.jpg", "real.png", "upper.PNG"]);
+    assert_eq!(discovery.num_skipped, 3); /* foo.notpng, no_extension and notes.txt */
+    assert!(discovery.unrecognized.is_empty());
+}
+
+#[test]
+fn content_detection_finds_misnamed_images_and_flags_fakes() {
+
+    let dir = TempDir::new().unwrap();
+    write_files(dir.path());
+    let mut config = test_config(dir.path());
+    config.detect_file_type_by_content = true;
+
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+
+    assert_eq!(file_names(&discovery.img_paths), vec!["foo.notpng", "no_extension", "photo.jpg", "real.png", "upper.PNG"]);
+    assert_eq!(discovery.num_skipped, 1); /* notes.txt */
+    assert_eq!(file_names(&discovery.unrecognized), vec!["fake.png"]);
+}
+
+/// writes small pngs at the given paths (relative to dir/search)
+fn write_tree(dir: &Path, paths: &[&str]) {
+
+    let img = DynamicImage::new_rgb8(16, 16);
+    for p in paths {
+        let full = dir.join("search").join(p);
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        img.save_with_format(&full, image::ImageFormat::Png).unwrap();
+    }
+}
+
+#[test]
+fn scan_options_filter_files_globally_and_per_root() {
+
+    let dir = TempDir::new().unwrap();
+    write_tree(dir.path(), &["a.png", "thumbs/b.png", "deep/er/c.png", ".hidden/d.png", "e.thumb.png"]);
+    fs::write(dir.path().join("search").join("tiny.png"), "x").unwrap();
+    let mut config = test_config(dir.path());
+
+    /* global options */
+    config.scan = ScanOptions {
+        exclude: vec!["thumbs/".to_string(), "*.thumb.png".to_string()],
+        skip_hidden: Some(true),
+        min_file_size: Some(8),
+        ..ScanOptions::default()
+    };
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+    assert_eq!(file_names(&discovery.img_paths), vec!["a.png", "c.png"]);
+
+    /* per-root options layered over the global ones */
+    config.search_dirs_paths = vec![SearchRoot::Table {
+        path: dir.path().join("search").to_string_lossy().to_string(),
+        scan: ScanOptions {
+            max_depth: Some(1),
+            skip_hidden: Some(false),
+            ..ScanOptions::default()
+        },
+        settings: RootSettings::default()
+    }];
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+    assert_eq!(file_names(&discovery.img_paths), vec!["a.png"]);
+
+    /* include globs */
+    config.scan = ScanOptions { include: vec!["deep/".to_string()], ..ScanOptions::default() };
+    config.search_dirs_paths = vec![SearchRoot::Path(dir.path().join("search").to_string_lossy().to_string())];
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+    assert_eq!(file_names(&discovery.img_paths), vec!["c.png"]);
+}
+
+#[test]
+fn overlapping_roots_are_deduplicated_and_sorted() {
+
+    let dir = TempDir::new().unwrap();
+    write_tree(dir.path(), &["b.png", "a.png", "sub/d.png", "sub/c.png"]);
+    let search = dir.path().join("search");
+    fs::write(search.join("sub").join("fake.png"), "not an image").unwrap();
+    let mut config = Config { detect_file_type_by_content: true, ..test_config(dir.path()) };
+
+    /* the same tree reached three ways: nested root, the root itself and a non-canonical path */
+    config.search_dirs_paths = vec![
+        SearchRoot::Path(search.join("sub").to_string_lossy().to_string()),
+        SearchRoot::Path(search.to_string_lossy().to_string()),
+        SearchRoot::Path(search.join("sub").join("..").to_string_lossy().to_string())
+    ];
+
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+
+    let canonical = fs::canonicalize(&search).unwrap();
+    let expected: Vec<String> = ["a.png", "b.png", "sub/c.png", "sub/d.png"].iter()
+        .map(|p| canonical.join(p).to_string_lossy().to_string())
+        .collect();
+    assert_eq!(discovery.img_paths, expected);
+    assert_eq!(discovery.num_duplicates, 6);
+
+    /* files that aren't images are reported once too */
+    assert_eq!(discovery.unrecognized, vec![canonical.join("sub/fake.png").to_string_lossy().to_string()]);
+
+    /* streaming yields the same set, in whatever order the walks produce it */
+    let mut streamed: Vec<String> = discover_image_files(&config, &config.search_dirs_paths, false).unwrap().collect();
+    streamed.sort();
+    assert_eq!(streamed, expected);
+}
+
+#[cfg(unix)]
+#[test]
+fn walk_errors_name_the_failing_entry() {
+
+    let dir = TempDir::new().unwrap();
+    write_tree(dir.path(), &["a.png"]);
+    let search = dir.path().join("search");
+    let broken = search.join("gone.png");
+    std::os::unix::fs::symlink(search.join("does_not_exist.png"), &broken).unwrap();
+
+    let mut config = test_config(dir.path());
+    let missing = dir.path().join("missing");
+    config.search_dirs_paths.push(SearchRoot::Path(missing.to_string_lossy().to_string()));
+
+    /* broken symlink is reported whether or not links are followed */
+    for follow in [false, true] {
+        config.scan.follow_symlinks = Some(follow);
+        let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+
+        assert_eq!(file_names(&discovery.img_paths), vec!["a.png"]);
+        assert_eq!(discovery.errors.len(), 2);
+
+        let kinds: Vec<(String, DiscoveryErrorKind)> = discovery.errors.iter().map(|e| (e.path.clone(), e.kind)).collect();
+        assert!(kinds.contains(&(missing.to_string_lossy().to_string(), DiscoveryErrorKind::NotFound)));
+        assert!(kinds.contains(&(broken.to_string_lossy().to_string(), DiscoveryErrorKind::BrokenSymlink)));
+        assert!(discovery.errors.iter().all(|e| !e.message.is_empty()));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_files_are_only_searched_when_following_symlinks() {
+
+    let dir = TempDir::new().unwrap();
+    write_tree(dir.path(), &["a.png"]);
+    let other = dir.path().join("other");
+    fs::create_dir_all(&other).unwrap();
+    DynamicImage::new_rgb8(16, 16).save_with_format(other.join("b.png"), image::ImageFormat::Png).unwrap();
+
+    let search = dir.path().join("search");
+    std::os::unix::fs::symlink(other.join("b.png"), search.join("link.png")).unwrap();
+    std::os::unix::fs::symlink(search.join("a.png"), search.join("same.png")).unwrap();
+
+    let mut config = test_config(dir.path());
+    config.scan.follow_symlinks = Some(false);
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+    assert_eq!(file_names(&discovery.img_paths), vec!["a.png"]);
+    assert_eq!(discovery.num_skipped, 2);
+
+    /* followed, reported by their targets' paths and only once */
+    config.scan.follow_symlinks = Some(true);
+    let discovery = find_image_files(&config, &config.search_dirs_paths).unwrap();
+    assert_eq!(file_names(&discovery.img_paths), vec!["a.png", "b.png"]);
+    assert_eq!(discovery.num_skipped, 0);
+}
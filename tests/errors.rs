@@ -0,0 +1,134 @@
+/* error handling tests: failure reasons, recorded failures (retried when the file or the params change) and corrupt cache entries in extract_single,
+   query images given as bytes in extract_query_bytes, bad config in load_config */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::{Config, ExtractParams};
+use local_reverse_image_search::error::{Error, FailureKind};
+use local_reverse_image_search::feature_matching::{extract_query, extract_query_bytes, extract_single};
+use local_reverse_image_search::utils::load_config;
+
+use image::DynamicImage;
+use std::fs;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+fn open_cache(dir: &TempDir) -> Cache {
+    Cache::open(&dir.path().join(".cache").to_string_lossy()).unwrap()
+}
+
+#[test]
+fn unreadable_images_return_io_and_decode_errors() {
+
+    let dir = TempDir::new().unwrap();
+    let cache = open_cache(&dir);
+
+    let missing = dir.path().join("missing.png").to_string_lossy().to_string();
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &missing);
+    assert!(matches!(res, Err(Error::Io { .. })), "{:?}", res.err());
+
+    let fake = dir.path().join("fake.png");
+    fs::write(&fake, "not an image").unwrap();
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &fake.to_string_lossy().to_string());
+    assert!(matches!(res, Err(Error::Decode { .. })), "{:?}", res.err());
+
+    let blank = dir.path().join("blank.png");
+    DynamicImage::new_rgb8(64, 64).save(&blank).unwrap();
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &blank.to_string_lossy().to_string());
+    assert_eq!(res.err().and_then(|err| err.failure_kind()), Some(FailureKind::NoKeypoints));
+}
+
+#[test]
+fn failures_are_recorded_until_the_file_changes() {
+
+    let dir = TempDir::new().unwrap();
+    let cache = open_cache(&dir);
+    let path = dir.path().join("broken.png");
+    let path_str = path.to_string_lossy().to_string();
+
+    fs::write(&path, "not an image").unwrap();
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &path_str);
+    assert!(matches!(res, Err(Error::Decode { .. })), "{:?}", res.err());
+
+    /* not retried while unchanged */
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &path_str);
+    match res {
+        Err(Error::PreviousFailure { failure }) => assert_eq!(failure.kind, FailureKind::Corrupt),
+        res => panic!("expected a recorded failure, got {:?}", res.err())
+    }
+
+    /* fixed file (different size) gets retried */
+    fs::copy(QUERY_IMG_PATH, &path).unwrap();
+    let (_, cached) = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &path_str).unwrap();
+    assert!(!cached);
+
+    /* and so do images that failed with other params */
+    let blank = dir.path().join("blank.png");
+    let blank_str = blank.to_string_lossy().to_string();
+    DynamicImage::new_rgb8(64, 64).save(&blank).unwrap();
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &blank_str);
+    assert!(matches!(res, Err(Error::NoKeypoints { .. })), "{:?}", res.err());
+    let res = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &blank_str);
+    assert!(matches!(res, Err(Error::PreviousFailure { .. })), "{:?}", res.err());
+
+    let mut lower = ExtractParams::default();
+    lower.extractor.detector_threshold = Some(0.00001);
+    let res = extract_single(&cache, &lower, &Vec::new(), &blank_str);
+    assert!(matches!(res, Err(Error::NoKeypoints { .. })), "{:?}", res.err());
+    let res = extract_single(&cache, &lower, &Vec::new(), &blank_str);
+    assert!(matches!(res, Err(Error::PreviousFailure { .. })), "{:?}", res.err());
+}
+
+#[test]
+fn corrupt_cache_entries_are_re_extracted() {
+
+    let dir = TempDir::new().unwrap();
+    let cache = open_cache(&dir);
+    let path = QUERY_IMG_PATH.to_string();
+
+    cache.insert(&path, b"garbage".to_vec()).unwrap();
+
+    let (features, cached) = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &path).unwrap();
+    assert!(!cached);
+    assert!(features.keypoints.len() > 0);
+
+    /* the corrupt entry was replaced */
+    let (_, cached) = extract_single(&cache, &ExtractParams::default(), &Vec::new(), &path).unwrap();
+    assert!(cached);
+}
+
+#[test]
+fn query_bytes_are_extracted_like_files() {
+
+    let dir = TempDir::new().unwrap();
+    let cache = open_cache(&dir);
+    let config = Config::default();
+    let name = "<stdin>".to_string();
+
+    let from_file = extract_query(&cache, &config, &QUERY_IMG_PATH.to_string()).unwrap();
+    let from_bytes = extract_query_bytes(&config, &fs::read(QUERY_IMG_PATH).unwrap(), &name).unwrap();
+    assert_eq!(from_bytes.len(), from_file.len());
+    for (b, f) in from_bytes.iter().zip(from_file.iter()) {
+        assert_eq!(b.orientation, f.orientation);
+        assert_eq!(b.features.keypoints.len(), f.features.keypoints.len());
+    }
+
+    let res = extract_query_bytes(&config, b"not an image", &name);
+    assert!(matches!(res, Err(Error::Decode { .. })), "{:?}", res.err());
+    assert_eq!(res.err().unwrap().exit_code(), 4);
+}
+
+#[test]
+fn bad_config_is_a_config_error() {
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, "search_dirs_paths = 3").unwrap();
+
+    let res = load_config(&path.to_string_lossy().to_string());
+    assert!(matches!(res, Err(Error::Config { .. })));
+    assert_eq!(res.err().unwrap().exit_code(), 2);
+
+    let res = load_config(&dir.path().join("missing.toml").to_string_lossy().to_string());
+    assert!(matches!(res, Err(Error::Config { .. })));
+}
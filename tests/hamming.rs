@@ -0,0 +1,73 @@
+/* hamming kernel tests: every kernel the cpu supports agrees with counting bits one byte
+   at a time, and ratio test matching finds descriptors in a copy of themselves */
+
+use local_reverse_image_search::feature_matching::get_matches;
+use local_reverse_image_search::hamming::{distance, distances, distances_with, Kernel};
+use local_reverse_image_search::store::Block;
+use local_reverse_image_search::utils::XorShift;
+
+fn random_blocks(rng: &mut XorShift, n: usize) -> Vec<Block> {
+    (0..n).map(|_| {
+        let mut block: Block = [0; 64];
+        for byte in block.iter_mut() {
+            *byte = rng.below(256) as u8;
+        }
+        block
+    }).collect()
+}
+
+fn naive_distance(a: &Block, b: &Block) -> u32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+#[test]
+fn kernels_agree_with_naive_popcount() {
+
+    let mut rng = XorShift::new(3);
+    let mut targets = random_blocks(&mut rng, 257);
+    targets.push([0; 64]);
+    targets.push([0xff; 64]);
+    let query = targets[0];
+
+    let expected: Vec<u32> = targets.iter().map(|t| naive_distance(&query, t)).collect();
+    assert_eq!(expected[0], 0);
+
+    for kernel in [Kernel::Avx512, Kernel::Avx2, Kernel::Scalar].into_iter().filter(|k| k.is_supported()) {
+        let mut out = vec![u32::MAX; targets.len()];
+        distances_with(kernel, &query, &targets, &mut out);
+        assert_eq!(out, expected, "{:?} kernel", kernel);
+    }
+
+    let mut out = vec![0; targets.len()];
+    distances(&query, &targets, &mut out);
+    assert_eq!(out, expected);
+    assert_eq!(distance(&[0; 64], &[0xff; 64]), 512);
+}
+
+#[test]
+fn descriptors_match_a_noisy_copy_of_themselves() {
+
+    let mut rng = XorShift::new(11);
+    let search = random_blocks(&mut rng, 200);
+
+    /* a few flipped bits per descriptor, far closer than any other random descriptor */
+    let query: Vec<Block> = search.iter().take(50).map(|block| {
+        let mut noisy = *block;
+        for _ in 0..8 {
+            noisy[rng.below(64)] ^= 1 << rng.below(8);
+        }
+        noisy
+    }).collect();
+
+    let matches = get_matches(0.5, &query, &search, &[]);
+    assert_eq!(matches, (0..50).map(|i| (i, i)).collect::<Vec<(usize, usize)>>());
+
+    /* masked out search descriptors aren't matched */
+    let ignored: Vec<bool> = (0..search.len()).map(|i| i < 10).collect();
+    let matches = get_matches(0.5, &query, &search, &ignored);
+    assert_eq!(matches, (10..50).map(|i| (i, i)).collect::<Vec<(usize, usize)>>());
+
+    /* nothing to compare against */
+    assert!(get_matches(0.5, &query, &search[..1], &[]).is_empty());
+    assert!(get_matches(0.5, &query, &search[..2], &[true, false]).is_empty());
+}
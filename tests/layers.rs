@@ -0,0 +1,80 @@
+/* layered config tests: defaults, user and project files, profiles, LRIS_* environment
+   variables and --set overrides are merged in order, and each value's source is kept */
+
+use local_reverse_image_search::config::SortBy;
+use local_reverse_image_search::error::Error;
+use local_reverse_image_search::layers::{load_layered, user_config_path, ConfigSources, Source};
+
+use std::fs;
+use tempfile::TempDir;
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn config_layers_are_merged_in_order() {
+
+    let dir = TempDir::new().unwrap();
+    let user = dir.path().join("user.toml");
+    let project = dir.path().join("project.toml");
+    fs::write(&user, "cache_path = \"/tmp/user-cache\"\nnum_workers = 2\n\n[scan]\nmax_depth = 1\n\n[profile.fast]\nresize_dimensions = [128, 128]\n").unwrap();
+    fs::write(&project, "search_dirs_paths = [\"media\"]\nnum_workers = 4\n\n[scan]\nskip_hidden = true\n\n[profile.fast]\nnum_workers = 8\n").unwrap();
+
+    let mut sources = ConfigSources {
+        user_file: Some(user.clone()),
+        project_file: Some(project.to_string_lossy().to_string()),
+        ..ConfigSources::default()
+    };
+    let user_name = user.to_string_lossy().to_string();
+    let project_name = project.to_string_lossy().to_string();
+
+    /* files over the defaults, tables merged key by key */
+    let layered = load_layered(&sources).unwrap();
+    let config = &layered.config;
+    assert_eq!(config.cache_path, "/tmp/user-cache");
+    assert_eq!(config.num_workers, 4);
+    assert_eq!(config.scan.max_depth, Some(1));
+    assert_eq!(config.scan.skip_hidden, Some(true));
+    assert_eq!(config.resize_dimensions, [256, 256]);
+    assert_eq!(config.search_dirs_paths[0].path(), "media");
+    assert_eq!(layered.files, vec![user_name.clone(), project_name.clone()]);
+    assert_eq!(layered.source("num_workers"), Source::File(project_name.clone()));
+    assert_eq!(layered.source("scan.max_depth"), Source::File(user_name.clone()));
+    assert_eq!(layered.source("ratio_test_ratio"), Source::Default);
+    assert!(layered.show().lines().any(|line| line.starts_with("ratio_test_ratio = 0.5") && line.ends_with("# default")));
+
+    /* profile (merged from both files), then environment, then --set */
+    sources.env = env(&[("LRIS_PROFILE", "fast"), ("LRIS_NUM_WORKERS", "16"), ("LRIS_SCAN__MAX_DEPTH", "3"), ("LRIS_RESULTS__SORT_BY", "mtime"), ("HOME", "/nowhere")]);
+    sources.overrides = vec!["num_workers=32".to_string(), "cache_path = /tmp/set cache".to_string()];
+    let layered = load_layered(&sources).unwrap();
+    let config = &layered.config;
+    assert_eq!(config.resize_dimensions, [128, 128]);
+    assert_eq!(config.num_workers, 32);
+    assert_eq!(config.scan.max_depth, Some(3));
+    assert_eq!(config.results.sort_by, SortBy::Mtime);
+    assert_eq!(config.cache_path, "/tmp/set cache");
+    assert_eq!(layered.source("resize_dimensions"), Source::Profile { name: "fast".to_string(), file: user_name.clone() });
+    assert_eq!(layered.source("scan.max_depth").to_string(), "env LRIS_SCAN__MAX_DEPTH");
+    assert_eq!(layered.source("num_workers").to_string(), "--set num_workers=32");
+    assert!(!layered.value.contains_key("profile"));
+
+    /* --profile wins over LRIS_PROFILE */
+    sources.profile = Some("slow".to_string());
+    assert!(matches!(load_layered(&sources), Err(Error::Config { .. })));
+
+    sources.profile = None;
+    sources.overrides = vec!["num_workers".to_string()];
+    assert!(matches!(load_layered(&sources), Err(Error::Usage { .. })));
+
+    sources.overrides = vec!["num_workers=lots".to_string()];
+    assert!(matches!(load_layered(&sources), Err(Error::Config { .. })));
+
+    /* a config file that was asked for has to exist */
+    sources.overrides = Vec::new();
+    sources.project_file = Some(dir.path().join("missing.toml").to_string_lossy().to_string());
+    assert!(matches!(load_layered(&sources), Err(Error::Config { .. })));
+
+    assert_eq!(user_config_path(&env(&[("HOME", "/home/me")])).unwrap(), std::path::Path::new("/home/me/.config/local-reverse-image-search/config.toml"));
+    assert_eq!(user_config_path(&env(&[("HOME", "/home/me"), ("XDG_CONFIG_HOME", "/xdg")])).unwrap(), std::path::Path::new("/xdg/local-reverse-image-search/config.toml"));
+}
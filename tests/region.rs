@@ -0,0 +1,124 @@
+/* region of interest tests: parsing --roi and mask files, restricting the query's
+   keypoints (in every orientation) to the region with restrict_query, and locating
+   just the region in matched images */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::Config;
+use local_reverse_image_search::error::Error;
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_query, restrict_query, Orientation};
+use local_reverse_image_search::region::{load_mask, Region};
+
+mod common;
+use common::test_config;
+
+use std::fs;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+#[test]
+fn regions_are_parsed_and_contain_points() {
+
+    let rect: Region = "10, 20,30,40".parse().unwrap();
+    assert_eq!(rect, Region::Rect { x: 10.0, y: 20.0, w: 30.0, h: 40.0 });
+    assert_eq!(rect.bounds(), [10.0, 20.0, 30.0, 40.0]);
+    assert!(rect.contains([10.0, 20.0]));
+    assert!(rect.contains([39.9, 59.9]));
+    assert!(!rect.contains([40.0, 30.0]));
+    assert!(!rect.contains([5.0, 30.0]));
+
+    for bad in ["10,20,30", "10,20,30,40,50", "a,b,c,d", "10,20,0,40"] {
+        assert!(bad.parse::<Region>().is_err(), "{}", bad);
+    }
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("mask.toml");
+    fs::write(&path, "[[regions]]\npoints = [[0, 0], [100, 0], [0, 100]]\n\n[[regions]]\nx = 200\ny = 200\nw = 10\nh = 10\n").unwrap();
+
+    let mask = load_mask(&path.to_string_lossy()).unwrap();
+    assert_eq!(mask.len(), 2);
+    assert_eq!(mask[0].bounds(), [0.0, 0.0, 100.0, 100.0]);
+    assert!(mask[0].contains([10.0, 10.0]));
+    assert!(!mask[0].contains([60.0, 60.0]));
+    assert!(mask[1].contains([205.0, 205.0]));
+
+    fs::write(&path, "[[regions]]\npoints = [[0, 0], [100, 0]]\n").unwrap();
+    assert!(matches!(load_mask(&path.to_string_lossy()), Err(Error::Config { .. })));
+}
+
+#[test]
+fn query_keypoints_are_restricted_in_every_orientation() {
+
+    let dir = TempDir::new().unwrap();
+    let cache = Cache::open(&dir.path().join(".cache").to_string_lossy()).unwrap();
+    let config = Config { match_flipped_horizontal: true, match_flipped_vertical: true, ..Config::default() };
+
+    let full = extract_query(&cache, &config, &QUERY_IMG_PATH.to_string()).unwrap();
+    let [w, h] = full[0].features.original_dims;
+
+    /* top left quarter */
+    let roi = vec![Region::Rect { x: 0.0, y: 0.0, w: w as f32 / 2.0, h: h as f32 / 2.0 }];
+    let mut query = full.clone();
+    restrict_query(&mut query, &roi).unwrap();
+
+    for (view, full_view) in query.iter().zip(full.iter()) {
+
+        let features = &view.features;
+        assert!(features.keypoints.len() > 0);
+        assert!(features.keypoints.len() < full_view.features.keypoints.len());
+        assert_eq!(features.keypoints.len(), features.descriptors.len());
+
+        /* the region's bounds, mirrored with the view */
+        let (qw, qh) = (w as f32 / 2.0, h as f32 / 2.0);
+        assert_eq!(view.roi, Some(match view.orientation {
+            Orientation::Original => [0.0, 0.0, qw, qh],
+            Orientation::FlippedHorizontal => [w as f32 - qw, 0.0, qw, qh],
+            Orientation::FlippedVertical => [0.0, h as f32 - qh, qw, qh]
+        }));
+
+        /* in original image coordinates, the quarter is on the other side of flipped views */
+        let [sx, sy] = features.scale();
+        for kp in features.keypoints.iter() {
+            let (x, y) = (kp.point.0 * sx, kp.point.1 * sy);
+            let (x, y) = match view.orientation {
+                Orientation::Original => (x, y),
+                Orientation::FlippedHorizontal => (w as f32 - x, y),
+                Orientation::FlippedVertical => (x, h as f32 - y)
+            };
+            assert!(x < w as f32 / 2.0 && y < h as f32 / 2.0, "{:?} keypoint at {}, {}", view.orientation, x, y);
+        }
+    }
+
+    /* nothing left to search for */
+    let mut query = full.clone();
+    let res = restrict_query(&mut query, &[Region::Rect { x: -10.0, y: -10.0, w: 5.0, h: 5.0 }]);
+    assert!(matches!(res, Err(Error::Usage { .. })));
+}
+
+#[test]
+fn matches_locate_the_region_not_the_whole_query() {
+
+    let dir = TempDir::new().unwrap();
+    let search = dir.path().join("search");
+    fs::create_dir_all(&search).unwrap();
+    let img = image::open(QUERY_IMG_PATH).unwrap();
+    img.save(search.join("copy.png")).unwrap();
+
+    let config = test_config(dir.path());
+    let cache = Cache::open(&config.cache_path).unwrap();
+    let mut query = extract_query(&cache, &config, &QUERY_IMG_PATH.to_string()).unwrap();
+    let [w, h] = query[0].features.original_dims;
+    let (w, h) = (w as f32, h as f32);
+
+    /* bottom right quarter, found where it is in an unchanged copy */
+    restrict_query(&mut query, &[Region::Rect { x: w / 2.0, y: h / 2.0, w: w / 2.0, h: h / 2.0 }]).unwrap();
+    let paths = vec![search.join("copy.png").to_string_lossy().to_string()];
+    let (info, failures) = calculate_similarities(&cache, &config, &query, paths).unwrap();
+    assert!(failures.is_empty(), "{:?}", failures);
+
+    let location = info[0].location.as_ref().expect("region wasn't located");
+    let expected = [w / 2.0, h / 2.0, w / 2.0, h / 2.0];
+    for (got, want) in location.bbox.iter().zip(expected.iter()) {
+        assert!((got - want).abs() < 0.05 * w.max(h), "located at {:?}, expected {:?}", location.bbox, expected);
+    }
+}
@@ -0,0 +1,72 @@
+/* result selection tests: select_results drops matches below min_matches or outside the path
+   globs, sorts by score, path, mtime or size and cuts off after top_k */
+
+use local_reverse_image_search::feature_matching::{select_results, ImgInfo, Orientation};
+use local_reverse_image_search::config::{Config, SortBy};
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn img_info(path: &Path, num_matches: u32) -> ImgInfo {
+    ImgInfo { path: path.to_string_lossy().to_string(), num_matches, location: None, orientation: Orientation::Original, source: 0 }
+}
+
+fn names(matches: &Vec<(f32, &ImgInfo)>) -> Vec<String> {
+    matches.iter().map(|(_, m)| Path::new(&m.path).file_name().unwrap().to_string_lossy().to_string()).collect()
+}
+
+#[test]
+fn matches_are_filtered_sorted_and_limited() {
+
+    let dir = TempDir::new().unwrap();
+    let mut config = Config { outlier_zscore_thresh: 1.0, ..Config::default() };
+
+    /* written oldest to newest, sizes increasing the other way */
+    let matched = [("a.png", 50, 300), ("c.png", 45, 200), ("b.jpg", 40, 100)];
+    fs::create_dir_all(dir.path().join("sub")).unwrap();
+    let mut info: Vec<ImgInfo> = Vec::new();
+    for (name, num_matches, size) in matched {
+        let path = match name {
+            "c.png" => dir.path().join("sub").join(name),
+            _ => dir.path().join(name)
+        };
+        fs::write(&path, vec![0u8; size]).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        info.push(img_info(&path, num_matches));
+    }
+    info.extend((0..20).map(|i| img_info(&dir.path().join(format!("noise_{}.png", i)), i % 2)));
+
+    let (_, _, matches) = select_results(&info, &config).unwrap();
+    assert_eq!(names(&matches), vec!["a.png", "c.png", "b.jpg"]);
+
+    config.results.sort_by = SortBy::Path;
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["a.png", "b.jpg", "c.png"]);
+
+    config.results.sort_by = SortBy::Mtime;
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["b.jpg", "c.png", "a.png"]);
+
+    config.results.sort_by = SortBy::Size;
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["a.png", "c.png", "b.jpg"]);
+
+    config.results.top_k = Some(2);
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["a.png", "c.png"]);
+
+    config.results.top_k = None;
+    config.results.min_matches = Some(42);
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["a.png", "c.png"]);
+
+    /* globs match file names anywhere, or whole directories */
+    config.results.min_matches = None;
+    config.results.sort_by = SortBy::Score;
+    config.results.paths = vec!["*.jpg".to_string()];
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["b.jpg"]);
+
+    config.results.paths = vec!["sub/".to_string(), "a.*".to_string()];
+    assert_eq!(names(&select_results(&info, &config).unwrap().2), vec!["a.png", "c.png"]);
+
+    assert!(matches!("newest".parse::<SortBy>(), Err(_)));
+    assert_eq!("mtime".parse::<SortBy>(), Ok(SortBy::Mtime));
+}
@@ -0,0 +1,318 @@
+/* end to end robustness tests: builds transformed copies of the bundled
+   renaissance.jpeg alongside unrelated distractor images, runs the full
+   find_image_files -> extract_single -> calculate_similarities pipeline
+   and checks which images get reported as matches (and that masked or shared
+   watermarks are ignored, and that odd image shapes don't break tiling) */
+
+use local_reverse_image_search::cache::Cache;
+use local_reverse_image_search::config::{Config, ExtractParams, QueryScore, RootSettings, ScanOptions, SearchRoot};
+use local_reverse_image_search::feature_matching::{calculate_similarities, extract_from_image, extract_query, find_outliers, merge_queries, ImgInfo, Orientation};
+use local_reverse_image_search::region::Region;
+use local_reverse_image_search::utils::{find_image_files, XorShift};
+use local_reverse_image_search::augment::Augmentation;
+
+mod common;
+use common::test_config;
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgb, RgbImage};
+use std::fs::File;
+use std::path::Path;
+use tempfile::TempDir;
+
+const QUERY_IMG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/renaissance.jpeg");
+
+/// z-score threshold used by the tests, each search has a single true match
+/// among NUM_DISTRACTORS images so the default from config.toml (10) is unreachable
+const TEST_ZSCORE_THRESH: f32 = 3.0;
+
+const NUM_DISTRACTORS: u64 = 24;
+
+/// random rectangles on a noisy background, shouldn't match anything
+fn distractor(rng: &mut XorShift) -> DynamicImage {
+
+    let (w, h) = (320, 240);
+    let mut out = RgbImage::from_fn(w, h, |_, _| {
+        let v = rng.below(40) as u8;
+        Rgb([v, v, v])
+    });
+
+    for _ in 0..12 {
+        let (x0, y0) = (rng.below(w as usize) as u32, rng.below(h as usize) as u32);
+        let (rw, rh) = (rng.below(80) as u32 + 10, rng.below(80) as u32 + 10);
+        let color = Rgb([rng.below(256) as u8, rng.below(256) as u8, rng.below(256) as u8]);
+        for x in x0..(x0 + rw).min(w) {
+            for y in y0..(y0 + rh).min(h) {
+                out.put_pixel(x, y, color);
+            }
+        }
+    }
+
+    DynamicImage::ImageRgb8(out)
+}
+
+/// transformed copies of the query image, (file name, image)
+fn variants(query: &DynamicImage, rng: &mut XorShift) -> Vec<(String, DynamicImage)> {
+    Augmentation::ALL.iter().map(|aug| {
+        let ext = match aug {
+            Augmentation::Recompress => "jpg",
+            _ => "png"
+        };
+        (format!("{}.{}", aug.name(), ext), aug.apply(query, rng))
+    }).collect()
+}
+
+/// writes img under name into dir, as jpeg or png depending on the extension
+fn write_image(dir: &Path, name: &str, img: &DynamicImage) {
+
+    std::fs::create_dir_all(dir).unwrap();
+    let mut f = File::create(dir.join(name)).unwrap();
+    let format = match name.ends_with(".jpg") {
+        true => ImageOutputFormat::Jpeg(90),
+        false => ImageOutputFormat::Png
+    };
+    img.write_to(&mut f, format).unwrap();
+}
+
+/// writes the distractor set into dir/distractors
+fn write_distractors(dir: &Path, rng: &mut XorShift) {
+    for i in 0..NUM_DISTRACTORS {
+        write_image(&dir.join("distractors"), &format!("distractor_{}.png", i), &distractor(rng));
+    }
+}
+
+/// searches dir/distractors plus a directory holding only the given variant for query_path,
+/// runs the full pipeline and returns the file names reported as matches
+/// along with the query orientation that matched
+fn run_search(dir: &Path, config: &Config, cache: &Cache, query_path: &str, name: &str, img: &DynamicImage) -> Vec<(String, Orientation)> {
+
+    let variant_dir = dir.join(format!("variant_{}", name));
+    write_image(&variant_dir, name, img);
+
+    let mut config = config.clone();
+    config.search_dirs_paths = vec![
+        SearchRoot::Path(dir.join("distractors").to_string_lossy().to_string()),
+        SearchRoot::Path(variant_dir.to_string_lossy().to_string())
+    ];
+
+    let query = extract_query(cache, &config, &query_path.to_string())
+                        .expect("unable to extract features from query image");
+
+    let img_paths = find_image_files(&config, &config.search_dirs_paths).unwrap().img_paths;
+    assert_eq!(img_paths.len(), NUM_DISTRACTORS as usize + 1, "not every generated image was discovered");
+
+    let (info, failed_paths) = calculate_similarities(cache, &config, &query, img_paths).unwrap();
+    assert!(failed_paths.is_empty(), "failed to open: {:?}", failed_paths);
+
+    matched_names(&info)
+}
+
+/// total matches of the distractors, ideally 0
+fn distractor_matches(info: &Vec<ImgInfo>) -> u32 {
+    info.iter().filter(|i| i.path.contains("distractor_")).map(|i| i.num_matches).sum()
+}
+
+fn matched_names(info: &Vec<ImgInfo>) -> Vec<(String, Orientation)> {
+    let (_, _, matches) = find_outliers(info, TEST_ZSCORE_THRESH);
+    matches.iter()
+        .map(|(_, m)| (Path::new(&m.path).file_name().unwrap().to_string_lossy().to_string(), m.orientation))
+        .collect()
+}
+
+#[test]
+fn transformed_variants_match_and_distractors_do_not() {
+
+    let dir = TempDir::new().unwrap();
+    let mut rng = XorShift::new(1);
+    let query = image::open(QUERY_IMG_PATH).unwrap();
+    let config = test_config(dir.path());
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    write_distractors(dir.path(), &mut rng);
+
+    /* report every failure at once rather than stopping at the first */
+    let mut missed: Vec<String> = Vec::new();
+    let mut false_positives: Vec<String> = Vec::new();
+
+    for (name, img) in variants(&query, &mut rng).iter() {
+        let matched: Vec<String> = run_search(dir.path(), &config, &cache, QUERY_IMG_PATH, name, img)
+                                        .into_iter()
+                                        .map(|(m, _)| m)
+                                        .collect();
+
+        if !matched.contains(name) {
+            missed.push(name.clone());
+        }
+        for m in matched.into_iter().filter(|m| m.starts_with("distractor")) {
+            if !false_positives.contains(&m) {
+                false_positives.push(m);
+            }
+        }
+    }
+
+    assert!(missed.is_empty(), "variants not reported as matches: {:?}", missed);
+    assert!(false_positives.is_empty(), "distractors reported as matches: {:?}", false_positives);
+}
+
+#[test]
+fn mirrored_variants_match_in_flip_mode() {
+
+    let dir = TempDir::new().unwrap();
+    let mut rng = XorShift::new(2);
+    let query = image::open(QUERY_IMG_PATH).unwrap();
+    let mut config = test_config(dir.path());
+    config.match_flipped_horizontal = true;
+    config.match_flipped_vertical = true;
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    write_distractors(dir.path(), &mut rng);
+
+    let matched = run_search(dir.path(), &config, &cache, QUERY_IMG_PATH, "mirrored_h.png", &query.fliph());
+    assert_eq!(matched, vec![("mirrored_h.png".to_string(), Orientation::FlippedHorizontal)]);
+
+    let matched = run_search(dir.path(), &config, &cache, QUERY_IMG_PATH, "mirrored_v.png", &query.flipv());
+    assert_eq!(matched.len(), 1, "expected only mirrored_v.png to match, got {:?}", matched);
+    assert_eq!(matched[0].0, "mirrored_v.png");
+    assert_ne!(matched[0].1, Orientation::Original);
+}
+
+#[test]
+fn small_crop_matches_full_image_in_tiled_mode() {
+
+    let dir = TempDir::new().unwrap();
+    let mut rng = XorShift::new(3);
+    let original = image::open(QUERY_IMG_PATH).unwrap();
+    let mut config = test_config(dir.path());
+    config.tile_grid_sizes = vec![2, 4];
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    write_distractors(dir.path(), &mut rng);
+
+    /* query with a small crop (1/5 of each side) of the full size image */
+    let (w, h) = original.dimensions();
+    let crop_path = dir.path().join("crop.png");
+    original.crop_imm(w * 2 / 5, h * 2 / 5, w / 5, h / 5).save(&crop_path).unwrap();
+
+    let matched = run_search(dir.path(), &config, &cache, &crop_path.to_string_lossy(), "original.png", &original);
+    assert_eq!(matched, vec![("original.png".to_string(), Orientation::Original)]);
+}
+
+#[test]
+fn skinny_images_are_extracted_without_tiles() {
+
+    /* too few rows for a 4 x 4 grid, too wide to skip tiling on size alone */
+    let strip = DynamicImage::ImageRgb8(RgbImage::from_fn(4000, 3, |x, _| Rgb([(x % 256) as u8, 0, 0])));
+    let features = extract_from_image(&strip, &ExtractParams::default(), &vec![4]);
+    assert!(features.tiles.is_empty());
+}
+
+#[test]
+fn shared_watermark_is_ignored_with_masks_and_stop_words() {
+
+    let dir = TempDir::new().unwrap();
+    let mut rng = XorShift::new(4);
+    let mut config = test_config(dir.path());
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    /* a detailed patch stamped in the bottom right corner of every image, query included */
+    let watermark = image::open(QUERY_IMG_PATH).unwrap().resize_exact(96, 96, FilterType::Triangle);
+    let stamp = |mut img: DynamicImage| {
+        imageops::overlay(&mut img, &watermark, 224, 144);
+        img
+    };
+    let mask = vec![Region::Rect { x: 224.0, y: 144.0, w: 96.0, h: 96.0 }];
+
+    for i in 0..NUM_DISTRACTORS {
+        write_image(&dir.path().join("distractors"), &format!("distractor_{}.png", i), &stamp(distractor(&mut rng)));
+    }
+    let photo = stamp(distractor(&mut rng));
+    write_image(&dir.path().join("photos"), "photo.png", &photo);
+    let query_path = dir.path().join("query.png");
+    photo.save(&query_path).unwrap();
+
+    let query = extract_query(&cache, &config, &query_path.to_string_lossy().to_string()).unwrap();
+    let search = |config: &Config| {
+        let img_paths = find_image_files(config, &config.search_dirs_paths).unwrap().img_paths;
+        let (info, failed_paths) = calculate_similarities(&cache, config, &query, img_paths).unwrap();
+        assert!(failed_paths.is_empty(), "failed to open: {:?}", failed_paths);
+        info
+    };
+
+    /* the watermark alone matches every distractor */
+    let roots = |mask: Vec<Region>| vec![
+        SearchRoot::Table { path: dir.path().join("distractors").to_string_lossy().to_string(), scan: ScanOptions { mask, ..ScanOptions::default() }, settings: RootSettings::default() },
+        SearchRoot::Path(dir.path().join("photos").to_string_lossy().to_string())
+    ];
+    config.search_dirs_paths = roots(Vec::new());
+    let unmasked = distractor_matches(&search(&config));
+    assert!(unmasked >= NUM_DISTRACTORS as u32, "watermark matched only {} times", unmasked);
+
+    /* masked out in the distractors' directory */
+    config.search_dirs_paths = roots(mask);
+    let info = search(&config);
+    assert!(distractor_matches(&info) * 4 < unmasked, "{} of {} watermark matches left with a mask", distractor_matches(&info), unmasked);
+    assert_eq!(matched_names(&info), vec![("photo.png".to_string(), Orientation::Original)]);
+
+    /* found without a mask, in more than half of the images */
+    config.search_dirs_paths = roots(Vec::new());
+    config.stop_word_fraction = 0.5;
+    let info = search(&config);
+    assert!(distractor_matches(&info) * 4 < unmasked, "{} of {} watermark matches left as stop-words", distractor_matches(&info), unmasked);
+    assert!(info.iter().any(|i| i.path.ends_with("photo.png") && i.num_matches > 0));
+}
+
+#[test]
+fn several_query_images_are_searched_together() {
+
+    let dir = TempDir::new().unwrap();
+    let mut rng = XorShift::new(5);
+    let original = image::open(QUERY_IMG_PATH).unwrap();
+    let mut config = test_config(dir.path());
+    let cache = Cache::open(&config.cache_path).unwrap();
+
+    write_distractors(dir.path(), &mut rng);
+    write_image(&dir.path().join("photos"), "original.png", &original);
+    config.search_dirs_paths = vec![
+        SearchRoot::Path(dir.path().join("distractors").to_string_lossy().to_string()),
+        SearchRoot::Path(dir.path().join("photos").to_string_lossy().to_string())
+    ];
+
+    /* an unrelated image and the two halves of the original, as if photographed separately */
+    let (w, h) = original.dimensions();
+    let views = [
+        ("unrelated.png", distractor(&mut rng)),
+        ("left.png", original.crop_imm(0, 0, w / 2, h)),
+        ("right.png", original.crop_imm(w / 2, 0, w - w / 2, h))
+    ];
+    let queries = views.iter().map(|(name, img)| {
+        let path = dir.path().join(name);
+        img.save(&path).unwrap();
+        extract_query(&cache, &config, &path.to_string_lossy().to_string()).unwrap()
+    }).collect();
+    let query = merge_queries(queries);
+    assert_eq!(query.iter().map(|view| view.source).collect::<Vec<usize>>(), vec![0, 1, 2]);
+
+    let search = |config: &Config| {
+        let img_paths = find_image_files(config, &config.search_dirs_paths).unwrap().img_paths;
+        let (info, failed_paths) = calculate_similarities(&cache, config, &query, img_paths).unwrap();
+        assert!(failed_paths.is_empty(), "failed to open: {:?}", failed_paths);
+        info
+    };
+    let original_info = |info: &Vec<ImgInfo>| -> (u32, usize) {
+        let i = info.iter().find(|i| i.path.ends_with("original.png")).unwrap();
+        (i.num_matches, i.source)
+    };
+
+    let info = search(&config);
+    assert_eq!(matched_names(&info), vec![("original.png".to_string(), Orientation::Original)]);
+    let (best, source) = original_info(&info);
+    assert_ne!(source, 0, "matched best with the unrelated query image");
+
+    /* both halves add up */
+    config.query_score = QueryScore::Sum;
+    let info = search(&config);
+    assert_eq!(matched_names(&info), vec![("original.png".to_string(), Orientation::Original)]);
+    let (sum, sum_source) = original_info(&info);
+    assert!(sum > best, "sum of matches {} isn't more than the best view's {}", sum, best);
+    assert_eq!(sum_source, source);
+}
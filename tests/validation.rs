@@ -0,0 +1,107 @@
+/* config validation tests: validate reports every out of range value and missing search directory
+   at once and normalizes extensions, check_layered adds unknown keys (in search directory tables too) and file:line locations */
+
+use local_reverse_image_search::config::{Config, ExtractorOptions, RootSettings, ScanOptions, SearchRoot};
+use local_reverse_image_search::error::Error;
+use local_reverse_image_search::layers::{load_layered, ConfigSources};
+use local_reverse_image_search::validation::{check, check_layered, validate};
+
+use std::fs;
+use tempfile::TempDir;
+
+#[test]
+fn every_config_problem_is_reported_at_once() {
+
+    let dir = TempDir::new().unwrap();
+    let search = dir.path().join("search");
+    fs::create_dir_all(&search).unwrap();
+
+    let mut config = Config {
+        search_dirs_paths: vec![SearchRoot::Path(search.to_string_lossy().to_string())],
+        valid_file_extensions: vec![".PNG".to_string(), "png".to_string(), "jpg".to_string()],
+        ..Config::default()
+    };
+    assert_eq!(validate(&mut config), Vec::new());
+    assert_eq!(config.valid_file_extensions, vec!["png", "jpg"]);
+
+    config.ratio_test_ratio = 1.5;
+    config.resize_dimensions = [0, 256];
+    config.tile_grid_sizes = vec![2, 0];
+    config.search_dirs_paths.push(SearchRoot::Path(dir.path().join("missing").to_string_lossy().to_string()));
+    config.scan.min_file_size = Some(100);
+    config.scan.max_file_size = Some(10);
+    config.results.top_k = Some(0);
+
+    let keys: Vec<String> = validate(&mut config).into_iter().map(|problem| problem.key).collect();
+    assert_eq!(keys, vec!["ratio_test_ratio", "resize_dimensions", "tile_grid_sizes", "search_dirs_paths[1]", "scan.min_file_size", "results.top_k"]);
+
+    config.valid_file_extensions = vec![".".to_string()];
+    assert!(validate(&mut config).iter().any(|problem| problem.key == "valid_file_extensions"));
+
+    /* a search directory's overrides are checked like the global values */
+    let settings = RootSettings {
+        valid_file_extensions: Some(vec![".TIF".to_string()]),
+        ratio_test_ratio: Some(0.0),
+        extractor: ExtractorOptions { num_sublevels: Some(0), ..ExtractorOptions::default() },
+        ..RootSettings::default()
+    };
+    let mut config = Config {
+        search_dirs_paths: vec![SearchRoot::Table { path: search.to_string_lossy().to_string(), scan: ScanOptions::default(), settings }],
+        ..Config::default()
+    };
+    let keys: Vec<String> = validate(&mut config).into_iter().map(|problem| problem.key).collect();
+    assert_eq!(keys, vec!["search_dirs_paths[0].extractor.num_sublevels", "search_dirs_paths[0].ratio_test_ratio"]);
+    assert_eq!(config.search_dirs_paths[0].settings(&config).valid_file_extensions, vec!["tif"]);
+}
+
+#[test]
+fn config_problems_point_at_their_file_and_line() {
+
+    let dir = TempDir::new().unwrap();
+    let search = dir.path().join("search");
+    fs::create_dir_all(&search).unwrap();
+    let project = dir.path().join("config.toml");
+    fs::write(&project, format!(
+        "search_dirs_paths = [ {:?} ]\nratio_test_ratio = 2\nnum_wrokers = 4\n\n[scan]\nskip_hidden = true\nmax_dpeth = 2\n\n[profile.fast]\nresize_dimensions = [0, 0]\n",
+        search.to_string_lossy()
+    )).unwrap();
+
+    /* no HOME in the environment given, so no user config file */
+    let mut sources = ConfigSources { project_file: Some(project.to_string_lossy().to_string()), ..ConfigSources::default() };
+
+    let mut layered = load_layered(&sources).unwrap();
+    let file = project.to_string_lossy().to_string();
+    let problems: Vec<String> = check_layered(&mut layered).iter().map(|problem| problem.to_string()).collect();
+    assert_eq!(problems, vec![
+        format!("{}:3: num_wrokers: unknown key", file),
+        format!("{}:7: scan.max_dpeth: unknown key", file),
+        format!("{}:2: ratio_test_ratio: is 2, has to be more than 0 and at most 1", file)
+    ]);
+
+    /* problems from a profile point into the profile, from overrides at the override */
+    sources.profile = Some("fast".to_string());
+    sources.overrides = vec!["ratio_test_ratio=0.5".to_string(), "stop_word_fraction=2".to_string()];
+    let mut layered = load_layered(&sources).unwrap();
+    let problems: Vec<String> = check_layered(&mut layered).iter().map(|problem| problem.to_string()).collect();
+    assert!(problems.contains(&format!("{}:10: resize_dimensions: is [0, 0], width and height have to be at least 1", file)), "{:?}", problems);
+    assert!(problems.contains(&"--set stop_word_fraction=2: stop_word_fraction: is 2, has to be between 0 (disabled) and 1".to_string()), "{:?}", problems);
+
+    let res = check(&mut layered);
+    assert!(matches!(res, Err(Error::Config { .. })));
+    assert_eq!(res.err().unwrap().exit_code(), 2);
+
+    /* typos in search directory tables, and tables without a path */
+    let roots = dir.path().join("roots.toml");
+    fs::write(&roots, format!(
+        "search_dirs_paths = [\n  {{ path = {:?}, exlude = [ \"x/\" ], ratio_tset_ratio = 0.5 }},\n  {{ max_depth = 2 }}\n]\n",
+        search.to_string_lossy()
+    )).unwrap();
+    let file = roots.to_string_lossy().to_string();
+    let mut layered = load_layered(&ConfigSources { project_file: Some(file.clone()), ..ConfigSources::default() }).unwrap();
+    let problems: Vec<String> = check_layered(&mut layered).iter().map(|problem| problem.to_string()).collect();
+    assert_eq!(problems, vec![
+        format!("{}:1: search_dirs_paths[0].exlude: unknown key", file),
+        format!("{}:1: search_dirs_paths[0].ratio_tset_ratio: unknown key", file),
+        format!("{}:1: search_dirs_paths[1].path: is missing, every search directory needs a path", file)
+    ]);
+}